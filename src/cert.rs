@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The application URI Telegraf's OPC UA client announces, servers check it against the certificate.
+const APPLICATION_URI: &str = "urn:telegraf:gopcua:client";
+/// File names of the generated pair, next to the config and in /etc/telegraf on the box.
+const CERTIFICATE_FILE: &str = "telegraf_client.crt";
+const PRIVATE_KEY_FILE: &str = "telegraf_client.key";
+
+/// The first OpenSSL release whose `req` takes `-addext`.
+const MINIMUM_OPENSSL: [u32; 3] = [1, 1, 1];
+
+fn supports_addext(version: &str) -> bool {
+    // "OpenSSL 1.1.1w  11 Sep 2023", other builds like LibreSSL are given the benefit of the doubt
+    let mut words = version.split_whitespace();
+    if words.next() != Some("OpenSSL") {
+        return true;
+    }
+    let number: String = words
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let parts: Vec<u32> = number
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect();
+    parts.as_slice() >= MINIMUM_OPENSSL.as_slice()
+}
+
+fn check_openssl() -> Result<(), Box<dyn std::error::Error>> {
+    let output = Command::new("openssl")
+        .arg("version")
+        .output()
+        .map_err(|e| {
+            format!(
+                "unable to run openssl: {}, --gen-cert needs OpenSSL 1.1.1 or newer on the PATH",
+                e
+            )
+        })?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    log::debug!("Found {}", version);
+    if !supports_addext(&version) {
+        return Err(format!(
+            "--gen-cert needs OpenSSL 1.1.1 or newer to add the certificate's extensions, found {}",
+            version
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Where the client certificate and private key of `directory` are, whether they exist or not.
+pub fn certificate_paths(directory: &Path) -> (PathBuf, PathBuf) {
+    (
+        directory.join(CERTIFICATE_FILE),
+        directory.join(PRIVATE_KEY_FILE),
+    )
+}
+
+/// Returns the client certificate and private key in `directory`, generating them with openssl
+/// when they are missing. An existing pair is kept, since the OPC UA server has to be told to
+/// trust a new certificate again.
+pub fn client_certificate(
+    directory: &Path,
+) -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>> {
+    let (certificate, private_key) = certificate_paths(directory);
+    if certificate.exists() && private_key.exists() {
+        say!("Using the client certificate {}", certificate.display());
+        return Ok((certificate, private_key));
+    }
+    // Half a pair is of no use, but the key may be the only copy of one the server trusts
+    for existing in [&certificate, &private_key] {
+        if existing.exists() {
+            println!(
+                "Warning: overwriting {}, its other half is missing.",
+                existing.display()
+            );
+        }
+    }
+
+    check_openssl()?;
+    std::fs::create_dir_all(directory)?;
+    let subject_alt_name = format!("subjectAltName=URI:{}", APPLICATION_URI);
+    let mut command = Command::new("openssl");
+    command
+        .args(["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-sha256"])
+        .args(["-days", "3650", "-subj", "/CN=Telegraf"])
+        .args(["-addext", &subject_alt_name])
+        .args([
+            "-addext",
+            "keyUsage=critical,digitalSignature,nonRepudiation,keyEncipherment,dataEncipherment",
+        ])
+        .args(["-addext", "extendedKeyUsage=clientAuth,serverAuth"])
+        .arg("-keyout")
+        .arg(&private_key)
+        .arg("-out")
+        .arg(&certificate);
+    log::debug!("Running {:?}", command);
+    let output = command
+        .output()
+        .map_err(|e| format!("unable to run openssl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "openssl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    report!(
+        "Generated the client certificate {} for {}, the OPC UA server has to trust it before Telegraf can connect",
+        certificate.display(),
+        APPLICATION_URI
+    );
+    Ok((certificate, private_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openssl_versions_with_addext() {
+        assert!(supports_addext("OpenSSL 1.1.1w  11 Sep 2023"));
+        assert!(supports_addext(
+            "OpenSSL 3.0.2 15 Mar 2022 (Library: OpenSSL 3.0.2 15 Mar 2022)"
+        ));
+        assert!(supports_addext("OpenSSL 1.1.10"));
+        assert!(supports_addext("LibreSSL 3.3.6"));
+    }
+
+    #[test]
+    fn openssl_versions_without_addext() {
+        assert!(!supports_addext("OpenSSL 1.1.0l  10 Sep 2019"));
+        assert!(!supports_addext("OpenSSL 1.0.2k-fips  26 Jan 2017"));
+        assert!(!supports_addext("OpenSSL"));
+    }
+}
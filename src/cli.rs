@@ -0,0 +1,799 @@
+use clap::{Arg, ArgAction, Command};
+use std::ffi::OsString;
+use std::{env, path::PathBuf};
+
+const SUBCOMMANDS: [&str; 9] = [
+    "generate",
+    "send",
+    "send-file",
+    "pull",
+    "diff",
+    "backup",
+    "restore",
+    "completions",
+    "help",
+];
+
+fn get_default_path() -> PathBuf {
+    // Returns the default path by getting the current executable's directory
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    path
+}
+
+pub fn args() -> Vec<OsString> {
+    // Running without a subcommand, e.g. by double-clicking the .exe, generates a config like before
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let has_subcommand = first_positional(args.get(1..).unwrap_or_default())
+        .is_some_and(|arg| SUBCOMMANDS.contains(&arg));
+    let top_level_info = matches!(
+        args.get(1).and_then(|arg| arg.to_str()),
+        Some("-h" | "--help" | "-V" | "--version")
+    );
+    if !has_subcommand && !top_level_info {
+        args.insert(1, "generate".into());
+    }
+    args
+}
+
+fn first_positional(args: &[OsString]) -> Option<&str> {
+    // Skips the flags and the values they take, so `--host send` or `-f backup` isn't read as a subcommand
+    let cli = build_cli();
+    let generate = cli.find_subcommand("generate").unwrap();
+    let flags: Vec<&Arg> = cli
+        .get_arguments()
+        .chain(generate.get_arguments())
+        .collect();
+    let takes_value = |is_flag: &dyn Fn(&Arg) -> bool| {
+        flags
+            .iter()
+            .any(|flag| is_flag(flag) && flag.get_action().takes_values())
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_str()?;
+        if arg == "--" {
+            return None;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            if !long.contains('=') && takes_value(&|flag| flag.get_long() == Some(long)) {
+                args.next();
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // In a group like -yf only the last flag can take the next argument, an earlier one takes the rest
+            for (index, short) in shorts.char_indices() {
+                if takes_value(&|flag| flag.get_short() == Some(short)) {
+                    if index + short.len_utf8() == shorts.len() {
+                        args.next();
+                    }
+                    break;
+                }
+            }
+        } else {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+fn folder_arg(help: &'static str) -> Arg {
+    Arg::new("folder")
+        .short('f')
+        .long("folder")
+        .value_name("FOLDER")
+        .help(help)
+        .default_value(get_default_path().into_os_string())
+}
+
+fn output_arg(help: &'static str) -> Arg {
+    Arg::new("output")
+        .short('o')
+        .long("output")
+        .value_name("PATH")
+        .help(help)
+}
+
+fn remote_path_arg(help: &'static str) -> Arg {
+    Arg::new("remote_path")
+        .long("remote-path")
+        .value_name("REMOTE_PATH")
+        .help(help)
+        .default_value(crate::ssh_utils::MAIN_CONFIG_PATH)
+}
+
+fn parse_mode(mode: &str) -> Result<i32, String> {
+    // Permission bits are written in octal, like chmod takes them
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match i32::from_str_radix(digits, 8) {
+        Ok(bits) if (0..=0o7777).contains(&bits) => Ok(bits),
+        _ => Err(format!(
+            "'{}' is not an octal file mode, expecting something like: 644",
+            mode
+        )),
+    }
+}
+
+fn parse_since(since: &str) -> Result<String, String> {
+    // influx backup takes the start of the range as an RFC3339 timestamp
+    chrono::DateTime::parse_from_rfc3339(since)
+        .map(|_| since.to_string())
+        .map_err(|_| {
+            format!(
+                "'{}' is not an RFC3339 timestamp, expecting something like: 2024-05-01T00:00:00Z",
+                since
+            )
+        })
+}
+
+fn parse_regex(pattern: &str) -> Result<regex::Regex, String> {
+    // Node names are matched anywhere, like grep does it
+    regex::Regex::new(pattern).map_err(|e| format!("'{}' is not a valid regex: {}", pattern, e))
+}
+
+fn parse_file_bucket(file_bucket: &str) -> Result<(String, String), String> {
+    // The file is named as found in the folder, without the path
+    match file_bucket.split_once('=') {
+        Some((file, bucket)) if !file.trim().is_empty() && !bucket.trim().is_empty() => {
+            Ok((file.trim().to_string(), bucket.trim().to_string()))
+        }
+        _ => Err(format!(
+            "'{}' is not a file and bucket, expecting something like: press_line.xml=presses",
+            file_bucket
+        )),
+    }
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    // Keys stay bare TOML keys, so they need no quoting in [global_tags]
+    let (key, value) = tag.split_once('=').ok_or_else(|| {
+        format!(
+            "'{}' is not a tag, expecting something like: site=plant-7",
+            tag
+        )
+    })?;
+    let key = key.trim();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!(
+            "'{}' is not a valid tag key, use letters, digits, '_' and '-'",
+            key
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn restart_args() -> [Arg; 4] {
+    [
+        Arg::new("restart_command")
+            .long("restart-command")
+            .value_name("COMMAND")
+            .help("Sets the command that restarts Telegraf on the IOT-2050")
+            .default_value("sudo systemctl restart telegraf"),
+        Arg::new("status_command")
+            .long("status-command")
+            .value_name("COMMAND")
+            .help("Sets the command that exits with 0 while Telegraf runs, skipped for a non-systemd --restart-command unless given")
+            .default_value("systemctl is-active --quiet telegraf"),
+        Arg::new("log_lines")
+            .long("log-lines")
+            .value_name("LINES")
+            .help("Sets how many lines of the Telegraf log are shown")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .default_value("20"),
+        Arg::new("restart_timeout")
+            .long("restart-timeout")
+            .value_name("SECONDS")
+            .help("Sets how long to wait for Telegraf to be running after the restart")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("30"),
+    ]
+}
+
+pub fn build_cli() -> Command {
+    Command::new("IOT2050 config handler")
+        .version("0.4")
+        .about("Generates Telegraf configs from XML files and manages the IOT-2050 they run on")
+        .subcommand_required(true)
+        .args([
+            Arg::new("iot_host")
+                .short('a')
+                .long("iot-host")
+                .value_name("IOT_HOST")
+                .help("Sets the IOT-2050 host address and port, repeat it or separate with commas for several boxes")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .env("IOT_HOST")
+                .default_value(env!("DEFAULT_IOT_IP"))
+                .global(true),
+            Arg::new("iot_password")
+                .short('w')
+                .long("iot-password")
+                .value_name("IOT_PASSWORD")
+                .help("Sets the IOT-2050 password")
+                .env("IOT_PASSWORD")
+                .hide_env_values(true)
+                .default_value(env!("DEFAULT_IOT_PASSWORD"))
+                .global(true),
+            Arg::new("identity")
+                .long("identity")
+                .value_name("KEY_PATH")
+                .help("Logs in to the IOT-2050 with this private key instead of the password")
+                .env("IOT_IDENTITY")
+                .global(true),
+            Arg::new("insecure")
+                .long("insecure")
+                .action(ArgAction::SetTrue)
+                .help("Connects to the IOT-2050 without checking its host key against known_hosts")
+                .global(true),
+            Arg::new("ssh_timeout")
+                .long("ssh-timeout")
+                .value_name("SECONDS")
+                .help("Sets how long to wait for the IOT-2050 to answer before giving up")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("10")
+                .global(true),
+            Arg::new("ssh_retries")
+                .long("ssh-retries")
+                .value_name("COUNT")
+                .help("Sets how many times to retry reaching the IOT-2050 after a dropped connection")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("3")
+                .global(true),
+            Arg::new("transfer_method")
+                .long("transfer-method")
+                .value_name("METHOD")
+                .help("Uploads files to the IOT-2050 over scp or, where scp is turned off, sftp")
+                .value_parser(["scp", "sftp"])
+                .default_value("scp")
+                .global(true),
+            Arg::new("concurrency")
+                .long("concurrency")
+                .value_name("HOSTS")
+                .help("Sets how many IOT-2050s are sent to at the same time")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("4")
+                .global(true),
+            Arg::new("host_retries")
+                .long("host-retries")
+                .value_name("COUNT")
+                .help("Tries an IOT-2050 that couldn't be reached again this many times when sending to several boxes")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("0")
+                .global(true),
+            Arg::new("max_failure_rate")
+                .long("max-failure-rate")
+                .value_name("PERCENT")
+                .help("Skips the remaining IOT-2050s once more than this percentage of all of them failed")
+                .value_parser(clap::value_parser!(u32).range(0..=100))
+                .global(true),
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count)
+                .help("Logs what is done to stderr, more with each -v: info, debug, trace. Also shows the Telegraf log after a successful restart")
+                .global(true),
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads flags, and the answers for each XML file, from a TOML file")
+                .global(true),
+            Arg::new("profile")
+                .long("profile")
+                .value_name("NAME")
+                .help("Fills in flags not given on the command line from a profile saved with --save-profile")
+                .global(true),
+            Arg::new("save_profile")
+                .long("save-profile")
+                .value_name("NAME")
+                .help("Saves the flags given on the command line as a profile")
+                .global(true),
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .help("Prints only errors and results, prompts that need a yes/no answer require --yes")
+                .global(true),
+            Arg::new("no_input")
+                .long("no-input")
+                .action(ArgAction::SetTrue)
+                .help("Never reads from stdin, stops with an error naming whatever would have been asked instead")
+                .global(true),
+            Arg::new("dry_run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Prints the generated config instead of writing it, nothing is done on the IOT-2050")
+                .global(true),
+        ])
+        .subcommand(
+            Command::new("generate")
+                .about("Generates a config file for Telegraf from XML files in the folder")
+                .args([
+                    folder_arg("Sets the folder containing the XML files"),
+                    output_arg("Sets the file the config is written to instead of FOLDER/telegraf.conf"),
+                    Arg::new("ip")
+                        .short('i')
+                        .long("ip")
+                        .value_name("IP")
+                        .help("Sets the OPC IP address")
+                        .env("OPC_IP")
+                        .default_value(env!("DEFAULT_IP")),
+                    Arg::new("username")
+                        .short('u')
+                        .long("username")
+                        .value_name("USERNAME")
+                        .help("Sets the OPC username")
+                        .env("OPC_USERNAME")
+                        .default_value(env!("DEFAULT_USERNAME")),
+                    Arg::new("password")
+                        .short('p')
+                        .long("password")
+                        .value_name("PASSWORD")
+                        .help("Sets the OPC password")
+                        .env("OPC_PASSWORD")
+                        .hide_env_values(true)
+                        .default_value(env!("DEFAULT_PASSWORD")),
+                    Arg::new("opc_auth")
+                        .long("opc-auth")
+                        .value_name("METHOD")
+                        .help("Sets how Telegraf authenticates against the OPC server")
+                        .value_parser(["username", "anonymous", "certificate"])
+                        .default_value("username"),
+                    Arg::new("certificate")
+                        .long("certificate")
+                        .value_name("CERT_PATH")
+                        .help("Sets the client certificate path on the IOT-2050, for --opc-auth certificate"),
+                    Arg::new("private_key")
+                        .long("private-key")
+                        .value_name("KEY_PATH")
+                        .help("Sets the client private key path on the IOT-2050, for --opc-auth certificate"),
+                    Arg::new("gen_cert")
+                        .long("gen-cert")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["certificate", "private_key"])
+                        .help("Generates a client certificate and key next to the config, unless they exist, and uses them for signing, encrypting and --opc-auth certificate"),
+                    Arg::new("security_policy")
+                        .long("security-policy")
+                        .value_name("POLICY")
+                        .help("Sets the OPC UA security policy")
+                        .value_parser(["None", "Basic128Rsa15", "Basic256", "Basic256Sha256", "auto"])
+                        .default_value("Basic256Sha256"),
+                    Arg::new("security_mode")
+                        .long("security-mode")
+                        .value_name("MODE")
+                        .help("Sets the OPC UA security mode")
+                        .value_parser(["None", "Sign", "SignAndEncrypt", "auto"])
+                        .default_value("SignAndEncrypt"),
+                    Arg::new("app_name")
+                        .long("app-name")
+                        .value_name("APP_NAME")
+                        .help("Sets the OPC UA application name advertised by Telegraf"),
+                    Arg::new("product_uri")
+                        .long("product-uri")
+                        .value_name("PRODUCT_URI")
+                        .help("Sets the OPC UA product URI advertised by Telegraf"),
+                    Arg::new("connect_timeout")
+                        .long("connect-timeout")
+                        .value_name("DURATION")
+                        .help("Sets how long Telegraf waits for the OPC UA server to accept a connection")
+                        .default_value("30s"),
+                    Arg::new("request_timeout")
+                        .long("request-timeout")
+                        .value_name("DURATION")
+                        .help("Sets how long Telegraf waits for an answer to an OPC UA request")
+                        .default_value("10s"),
+                    Arg::new("session_timeout")
+                        .long("session-timeout")
+                        .value_name("DURATION")
+                        .help("Sets the OPC UA session timeout of listener inputs")
+                        .default_value("20m"),
+                    Arg::new("publishing_interval")
+                        .long("publishing-interval")
+                        .value_name("DURATION")
+                        .help("Sets how often the OPC UA server publishes the changes of listener inputs, apart from their sampling interval"),
+                    Arg::new("listeners")
+                        .long("listeners")
+                        .value_name("all|none|INDEXES")
+                        .help("Chooses the listener (subscriber) files without asking, e.g. all, none or 1,3,5"),
+                    Arg::new("strict")
+                        .long("strict")
+                        .action(ArgAction::SetTrue)
+                        .help("Stops on XML files without any matching variables instead of leaving them out, and on built-in default credentials"),
+                    Arg::new("allow_default_credentials")
+                        .long("allow-default-credentials")
+                        .action(ArgAction::SetTrue)
+                        .help("Doesn't warn when the OPC UA or IOT-2050 credentials are still the built-in defaults"),
+                    Arg::new("tag")
+                        .long("tag")
+                        .value_name("KEY=VALUE")
+                        .help("Adds a global tag to every metric, repeat it for several tags")
+                        .value_parser(parse_tag)
+                        .action(ArgAction::Append),
+                    Arg::new("file_bucket")
+                        .long("file-bucket")
+                        .value_name("FILE=BUCKET")
+                        .help("Writes the metrics of one XML file to another InfluxDB bucket, repeat it for several files")
+                        .value_parser(parse_file_bucket)
+                        .action(ArgAction::Append),
+                    Arg::new("template")
+                        .long("template")
+                        .value_name("TEMPLATE_FILE")
+                        .help("Lays out the config from a file with {{placeholders}} instead of the built-in layout")
+                        .conflicts_with("merge"),
+                    Arg::new("deadband")
+                        .long("deadband")
+                        .value_name("VALUE")
+                        .help("Makes listeners only report numeric values that changed by more than VALUE")
+                        .value_parser(clap::value_parser!(f64)),
+                    Arg::new("deadband_type")
+                        .long("deadband-type")
+                        .value_name("TYPE")
+                        .help("Sets whether --deadband is an absolute change or a percent of the node's range")
+                        .value_parser(["absolute", "percent"])
+                        .default_value("absolute"),
+                    Arg::new("timestamp")
+                        .long("timestamp")
+                        .value_name("SOURCE")
+                        .help("Stamps values with the OPC UA server's time (source) or the time Telegraf read them (gather)")
+                        .value_parser(["source", "gather"])
+                        .default_value("source"),
+                    Arg::new("measurement_per_group")
+                        .long("measurement-per-group")
+                        .action(ArgAction::SetTrue)
+                        .help("Uses each group's name as its measurement instead of the shared plugin name"),
+                    Arg::new("auto_rename")
+                        .long("auto-rename")
+                        .action(ArgAction::SetTrue)
+                        .help("Appends a numeric suffix to group names already used by an earlier group"),
+                    Arg::new("dedupe")
+                        .long("dedupe")
+                        .action(ArgAction::SetTrue)
+                        .help("Drops nodes whose NodeId already appears in an earlier group"),
+                    Arg::new("all_namespaces")
+                        .long("all-namespaces")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("import")
+                        .help("Reads the variables of every namespace in an XML file, one group per namespace, instead of asking for the namespace"),
+                    Arg::new("browse_paths")
+                        .long("browse-paths")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("import")
+                        .help("Follows the references of the XML files to tag each node with its browse path, which also names nodes without a VariableMapping name"),
+                    Arg::new("include")
+                        .long("include")
+                        .value_name("REGEX")
+                        .conflicts_with("import")
+                        .help("Keeps only nodes whose BrowseName or VariableMapping name matches this regex, e.g. ^Temp_")
+                        .value_parser(parse_regex),
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("REGEX")
+                        .conflicts_with("import")
+                        .help("Leaves out nodes whose BrowseName or VariableMapping name matches this regex")
+                        .value_parser(parse_regex),
+                    Arg::new("namespace")
+                        .long("namespace")
+                        .value_name("NAMESPACE")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .conflicts_with_all(["import", "all_namespaces"])
+                        .help("Uses this namespace number or URI for every XML file instead of asking for it, namespaces set per file in the --config file still win"),
+                    Arg::new("token")
+                        .short('t')
+                        .long("token")
+                        .value_name("TOKEN_FOLDER")
+                        .help("Sets the location of the InfluxDB token.txt")
+                        .default_value(get_default_path().into_os_string()),
+                    Arg::new("agent_interval")
+                        .long("agent-interval")
+                        .value_name("DURATION")
+                        .help("Sets the default collection interval of the Telegraf agent")
+                        .default_value("1000ms"),
+                    Arg::new("flush_interval")
+                        .long("flush-interval")
+                        .value_name("DURATION")
+                        .help("Sets how often Telegraf flushes metrics to the outputs")
+                        .default_value("10s"),
+                    Arg::new("batch_size")
+                        .long("batch-size")
+                        .value_name("METRICS")
+                        .help("Sets the maximum number of metrics Telegraf writes per batch")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("10000"),
+                    Arg::new("buffer_limit")
+                        .long("buffer-limit")
+                        .value_name("METRICS")
+                        .help("Sets how many unwritten metrics Telegraf buffers per output")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("100000"),
+                    Arg::new("output_type")
+                        .long("output-type")
+                        .value_name("TYPE")
+                        .help("Sets where Telegraf writes the metrics to")
+                        .value_parser(["influxdb", "prometheus"])
+                        .default_value("influxdb"),
+                    Arg::new("prometheus_url")
+                        .long("prometheus-url")
+                        .value_name("URL")
+                        .help("Sets the Prometheus remote write URL, e.g. http://192.168.0.5:9090/api/v1/write")
+                        .required_if_eq("output_type", "prometheus"),
+                    Arg::new("influx_url")
+                        .long("influx-url")
+                        .value_name("URL")
+                        .help("Sets the InfluxDB URL Telegraf writes to, repeat it to write to several InfluxDBs")
+                        .action(ArgAction::Append)
+                        .default_value("http://127.0.0.1:8086"),
+                    Arg::new("influx_token")
+                        .long("influx-token")
+                        .value_name("TOKEN")
+                        .help("Sets the InfluxDB token instead of reading token.txt, once for all URLs or once per URL")
+                        .action(ArgAction::Append)
+                        .env("INFLUX_TOKEN")
+                        .hide_env_values(true),
+                    Arg::new("token_file")
+                        .long("token-file")
+                        .value_name("PATH")
+                        .help("Reads the InfluxDB token from this file instead of token.txt, once for all URLs or once per URL")
+                        .action(ArgAction::Append),
+                    Arg::new("influx_org")
+                        .long("influx-org")
+                        .value_name("ORG")
+                        .help("Sets the InfluxDB organization, once for all URLs or once per URL")
+                        .action(ArgAction::Append)
+                        .default_value("org"),
+                    Arg::new("influx_bucket")
+                        .long("influx-bucket")
+                        .value_name("BUCKET")
+                        .help("Sets the InfluxDB bucket, once for all URLs or once per URL")
+                        .action(ArgAction::Append)
+                        .default_value("line"),
+                    Arg::new("verify_token")
+                        .long("verify-token")
+                        .action(ArgAction::SetTrue)
+                        .help("Checks that InfluxDB accepts the token before generating, asking the IOT-2050 for URLs on 127.0.0.1 or localhost"),
+                    Arg::new("import")
+                        .long("import")
+                        .value_name("TELEGRAF_CONF")
+                        .help("Regenerates the config from a previously generated telegraf.conf instead of XML files"),
+                    Arg::new("json_summary")
+                        .long("json-summary")
+                        .value_name("PATH")
+                        .num_args(0..=1)
+                        .default_missing_value("-")
+                        .help("Prints a JSON summary of the generated config as the last output, or writes it to PATH"),
+                    Arg::new("check_endpoint")
+                        .long("check-endpoint")
+                        .action(ArgAction::SetTrue)
+                        .help("Checks that the OPC UA server answers and has an endpoint with the security policy, mode and user token type before generating, without logging in"),
+                    Arg::new("list_nodes")
+                        .long("list-nodes")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("import")
+                        .help("Lists the variables of each XML file and exits without asking anything or writing a config"),
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Prints --list-nodes as an aligned table or as CSV")
+                        .value_parser(["table", "csv"])
+                        .default_value("table")
+                        .requires("list_nodes"),
+                    Arg::new("validate_only")
+                        .long("validate-only")
+                        .action(ArgAction::SetTrue)
+                        .help("Checks that the generated config is valid TOML without writing or sending it"),
+                    Arg::new("merge")
+                        .long("merge")
+                        .action(ArgAction::SetTrue)
+                        .help("Replaces only the generated OPC UA inputs of an existing output file and keeps the rest of it"),
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Breaks a lock on the output folder that is older than an hour, left behind by a run that died"),
+                    Arg::new("append_inputs")
+                        .long("append-inputs")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["merge", "template", "import"])
+                        .help("Adds the inputs of the XML files found to the generated section of an existing output file, keeping its inputs, agent and outputs"),
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .help("Overwrites an existing output file without asking, with --quiet also uses the XML files found"),
+                    Arg::new("force_send")
+                        .long("force-send")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["dry_run", "validate_only"])
+                        .help("Uses the XML files found, overwrites an existing output file and sends it to the IOT-2050, all without asking"),
+                    Arg::new("no_backup")
+                        .long("no-backup")
+                        .action(ArgAction::SetTrue)
+                        .help("Does not keep a copy of the remote telegraf.conf when sending a new one"),
+                    remote_path_arg("Sets where the config is written on the IOT-2050, e.g. a file in /etc/telegraf/telegraf.d/"),
+                ])
+                .args(restart_args()),
+        )
+        .subcommand(
+            Command::new("send")
+                .about("Sends an existing telegraf.conf to the IOT-2050 and restarts Telegraf")
+                .args([
+                    folder_arg("Sets the folder containing the telegraf.conf"),
+                    output_arg("Sets the config file to send instead of FOLDER/telegraf.conf"),
+                    Arg::new("no_backup")
+                        .long("no-backup")
+                        .action(ArgAction::SetTrue)
+                        .help("Does not keep a copy of the remote telegraf.conf when sending a new one"),
+                    Arg::new("rollback")
+                        .long("rollback")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["output", "no_backup"])
+                        .help("Restores one of the backups kept on the IOT-2050 instead of sending a config"),
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .requires("rollback")
+                        .help("Restores the newest backup without asking"),
+                    remote_path_arg("Sets where the config is written on the IOT-2050, e.g. a file in /etc/telegraf/telegraf.d/"),
+                ])
+                .args(restart_args()),
+        )
+        .subcommand(
+            Command::new("send-file")
+                .about("Copies any file to a path on the IOT-2050, e.g. a processors config or a certificate")
+                .args([
+                    Arg::new("local")
+                        .long("local")
+                        .value_name("PATH")
+                        .help("Sets the file to send")
+                        .required(true),
+                    Arg::new("remote")
+                        .long("remote")
+                        .value_name("REMOTE_PATH")
+                        .help("Sets where the file is written on the IOT-2050")
+                        .required(true),
+                    Arg::new("mode")
+                        .long("mode")
+                        .value_name("MODE")
+                        .help("Sets the permission bits of the remote file in octal")
+                        .value_parser(parse_mode)
+                        .default_value("644"),
+                ]),
+        )
+        .subcommand(
+            Command::new("pull")
+                .about("Downloads the IOT-2050's telegraf.conf and shows how the local one differs")
+                .args([
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .help("Sets where the downloaded config is saved")
+                        .default_value("telegraf.conf.remote"),
+                    folder_arg("Sets the folder containing the local telegraf.conf"),
+                    output_arg("Sets the local config to compare against instead of FOLDER/telegraf.conf"),
+                    remote_path_arg("Sets which config on the IOT-2050 is downloaded"),
+                ]),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Shows which groups, nodes and settings differ between two telegraf.conf files")
+                .args([
+                    Arg::new("old")
+                        .value_name("OLD_CONFIG")
+                        .help("Sets the config to compare against, e.g. the one deployed")
+                        .required(true),
+                    Arg::new("new")
+                        .value_name("NEW_CONFIG")
+                        .help("Sets the changed config")
+                        .required(true),
+                ]),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Backs up data from the IOT-2050 to the current working directory")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("influx")
+                        .about("Backs up the InfluxDB v2 database")
+                        .args([
+                            Arg::new("no_compress")
+                                .long("no-compress")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("extract")
+                                .help("Copies the InfluxDB backup file by file instead of as a single tar.gz"),
+                            Arg::new("extract")
+                                .long("extract")
+                                .action(ArgAction::SetTrue)
+                                .help("Extracts the downloaded InfluxDB backup archive next to it"),
+                            Arg::new("keep")
+                                .long("keep")
+                                .value_name("COUNT")
+                                .help("Keeps only the newest COUNT local backups, removing older ones after a successful backup")
+                                .value_parser(clap::value_parser!(u32).range(1..)),
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help("Starts a new backup and downloads every file, instead of resuming an unfinished --no-compress backup"),
+                            Arg::new("max_rate")
+                                .long("max-rate")
+                                .value_name("KB/S")
+                                .help("Keeps the download below this many kilobytes per second, for metered links")
+                                .value_parser(clap::value_parser!(u64).range(1..)),
+                            Arg::new("backup_since")
+                                .long("backup-since")
+                                .value_name("TIMESTAMP")
+                                .help("Backs up only data written after this RFC3339 timestamp, e.g. 2024-05-01T00:00:00Z")
+                                .value_parser(parse_since),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("grafana")
+                        .about("Backs up the Grafana configuration")
+                        .args([
+                            Arg::new("dashboards")
+                                .long("dashboards")
+                                .action(ArgAction::SetTrue)
+                                .help("Also saves every dashboard as JSON through the Grafana HTTP API"),
+                            Arg::new("grafana_url")
+                                .long("grafana-url")
+                                .value_name("URL")
+                                .help("Sets the Grafana URL for --dashboards instead of port 3000 on the IOT-2050"),
+                            Arg::new("grafana_token")
+                                .long("grafana-token")
+                                .value_name("TOKEN")
+                                .help("Sets the Grafana API token for --dashboards")
+                                .env("GRAFANA_TOKEN")
+                                .hide_env_values(true),
+                        ]),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restores a backup onto the IOT-2050")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("influx")
+                        .about("Uploads an InfluxDB backup and restores it with influx restore")
+                        .args([
+                            Arg::new("backup")
+                                .value_name("BACKUP")
+                                .help("Sets the backup to restore, a .tar.gz from backup influx or an extracted backup folder")
+                                .required(true),
+                            Arg::new("full")
+                                .long("full")
+                                .action(ArgAction::SetTrue)
+                                .help("Replaces all data on the IOT-2050, including users and tokens, instead of only adding missing buckets"),
+                        ]),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Prints the tab completion script for a shell")
+                .arg(
+                    Arg::new("shell")
+                        .value_name("SHELL")
+                        .help("Sets the shell to print the script for")
+                        .value_parser(clap::value_parser!(clap_complete::Shell))
+                        .required(true),
+                ),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first(args: &[&str]) -> Option<String> {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        first_positional(&args).map(str::to_string)
+    }
+
+    #[test]
+    fn first_positional_skips_flag_values() {
+        assert_eq!(first(&["send", "--dry-run"]).as_deref(), Some("send"));
+        assert_eq!(first(&["--dry-run", "send"]).as_deref(), Some("send"));
+        assert_eq!(first(&["-a", "send", "-f", "backup"]), None);
+        assert_eq!(first(&["--iot-host=box", "pull"]).as_deref(), Some("pull"));
+        assert_eq!(first(&["-f", "backup"]), None);
+        assert_eq!(first(&["--", "send"]), None);
+    }
+}
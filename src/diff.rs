@@ -0,0 +1,334 @@
+use crate::format::{Node, OpcGroup};
+use crate::import::ImportedConfig;
+
+fn changed(lines: &mut Vec<String>, what: &str, old: &str, new: &str) {
+    // A "~" line for a setting whose value differs
+    if old != new {
+        lines.push(format!("~ {}: {} -> {}", what, quoted(old), quoted(new)));
+    }
+}
+
+fn quoted(value: &str) -> String {
+    // Empty values would otherwise vanish from the line
+    format!("\"{}\"", value)
+}
+
+fn tags_text(tags: &[(String, String)]) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn group_label(group: &OpcGroup) -> String {
+    let plugin = if group.is_listener {
+        "opcua_listener"
+    } else {
+        "opcua"
+    };
+    format!("{} group '{}'", plugin, group.name)
+}
+
+fn node_label(node: &Node) -> String {
+    format!(
+        "node {}={} '{}'",
+        node.identifier_type, node.identifier, node.name
+    )
+}
+
+fn matching<'a, T>(
+    old: &'a [T],
+    new: &'a [T],
+    same: impl Fn(&T, &T) -> bool,
+) -> (Vec<(&'a T, &'a T)>, Vec<&'a T>, Vec<&'a T>) {
+    // Pairs each old entry with the first unused new one that is the same, the rest were removed or added
+    let mut used = vec![false; new.len()];
+    let mut pairs = Vec::new();
+    let mut removed = Vec::new();
+    for old_entry in old {
+        match (0..new.len()).find(|&index| !used[index] && same(old_entry, &new[index])) {
+            Some(index) => {
+                used[index] = true;
+                pairs.push((old_entry, &new[index]));
+            }
+            None => removed.push(old_entry),
+        }
+    }
+    let added = new
+        .iter()
+        .zip(used)
+        .filter(|(_, used)| !used)
+        .map(|(entry, _)| entry)
+        .collect();
+    (pairs, removed, added)
+}
+
+fn diff_nodes(lines: &mut Vec<String>, label: &str, old: &[Node], new: &[Node]) {
+    let (pairs, removed, added) = matching(old, new, |a, b| {
+        a.identifier_type == b.identifier_type && a.identifier == b.identifier
+    });
+    for node in removed {
+        lines.push(format!("- {}: {}", label, node_label(node)));
+    }
+    for node in added {
+        lines.push(format!("+ {}: {}", label, node_label(node)));
+    }
+    for (old_node, new_node) in pairs {
+        let label = format!("{}, {}", label, node_label(new_node));
+        changed(
+            lines,
+            &format!("{} name", label),
+            &old_node.name,
+            &new_node.name,
+        );
+        changed(
+            lines,
+            &format!("{} tags", label),
+            &tags_text(&old_node.tags),
+            &tags_text(&new_node.tags),
+        );
+        changed(
+            lines,
+            &format!("{} sampling_interval", label),
+            old_node.sampling_interval.as_deref().unwrap_or_default(),
+            new_node.sampling_interval.as_deref().unwrap_or_default(),
+        );
+        let deadband = |node: &Node| {
+            node.deadband
+                .as_ref()
+                .map(|deadband| format!("{} {}", deadband.kind, deadband.value))
+                .unwrap_or_default()
+        };
+        changed(
+            lines,
+            &format!("{} deadband", label),
+            &deadband(old_node),
+            &deadband(new_node),
+        );
+    }
+}
+
+/// Compares two configs by what they read and where they write, not by their text.
+/// Every difference is one line starting with `+` for added, `-` for removed or `~` for changed.
+pub fn diff_configs(old: &ImportedConfig, new: &ImportedConfig) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    changed(&mut lines, "OPC UA server", &old.opc.ip, &new.opc.ip);
+    changed(
+        &mut lines,
+        "security_policy",
+        &old.opc.security_policy,
+        &new.opc.security_policy,
+    );
+    changed(
+        &mut lines,
+        "security_mode",
+        &old.opc.security_mode,
+        &new.opc.security_mode,
+    );
+    changed(&mut lines, "username", &old.opc.username, &new.opc.username);
+    changed(
+        &mut lines,
+        "agent interval",
+        &old.agent.interval,
+        &new.agent.interval,
+    );
+    changed(
+        &mut lines,
+        "flush_interval",
+        &old.agent.flush_interval,
+        &new.agent.flush_interval,
+    );
+    changed(
+        &mut lines,
+        "global_tags",
+        &tags_text(&old.global_tags),
+        &tags_text(&new.global_tags),
+    );
+
+    let (pairs, removed, added) = matching(&old.outputs, &new.outputs, |a, b| a.url == b.url);
+    for output in removed {
+        lines.push(format!("- output {}", output.url));
+    }
+    for output in added {
+        lines.push(format!("+ output {}", output.url));
+    }
+    for (old_output, new_output) in pairs {
+        let label = format!("output {}", new_output.url);
+        changed(
+            &mut lines,
+            &format!("{} organization", label),
+            &old_output.organization,
+            &new_output.organization,
+        );
+        changed(
+            &mut lines,
+            &format!("{} bucket", label),
+            &old_output.bucket,
+            &new_output.bucket,
+        );
+        if old_output.token != new_output.token {
+            lines.push(format!("~ {} token changed", label));
+        }
+    }
+
+    let (pairs, removed, added) = matching(&old.groups, &new.groups, |a, b| {
+        a.is_listener == b.is_listener && a.name == b.name
+    });
+    for group in removed {
+        lines.push(format!(
+            "- {} ({} nodes)",
+            group_label(group),
+            group.nodes.len()
+        ));
+    }
+    for group in added {
+        lines.push(format!(
+            "+ {} ({} nodes)",
+            group_label(group),
+            group.nodes.len()
+        ));
+    }
+    for (old_group, new_group) in pairs {
+        let label = group_label(new_group);
+        changed(
+            &mut lines,
+            &format!("{} interval", label),
+            &old_group.interval,
+            &new_group.interval,
+        );
+        changed(
+            &mut lines,
+            &format!("{} namespace", label),
+            &old_group.namespace,
+            &new_group.namespace,
+        );
+        changed(
+            &mut lines,
+            &format!("{} measurement", label),
+            old_group.measurement.as_deref().unwrap_or_default(),
+            new_group.measurement.as_deref().unwrap_or_default(),
+        );
+        changed(
+            &mut lines,
+            &format!("{} timestamp", label),
+            &old_group.timestamp,
+            &new_group.timestamp,
+        );
+        changed(
+            &mut lines,
+            &format!("{} bucket", label),
+            old_group.bucket.as_deref().unwrap_or_default(),
+            new_group.bucket.as_deref().unwrap_or_default(),
+        );
+        diff_nodes(&mut lines, &label, &old_group.nodes, &new_group.nodes);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::import_config;
+
+    const CONFIG: &str = r#"
+[agent]
+interval = "1000ms"
+
+[[outputs.influxdb_v2]]
+urls = ["http://127.0.0.1:8086"]
+token = "secret"
+organization = "siemens"
+bucket = "machines"
+
+[[inputs.opcua]]
+name = "opcua"
+interval = "1000ms"
+endpoint = "opc.tcp://192.168.0.20:4840"
+username = "admin"
+    [[inputs.opcua.group]]
+      name = "Press"
+      namespace = "2"
+      identifier_type = "i"
+      nodes = [
+        {name="Temp", identifier="5", tags=[["datatype", "Double"]]},
+        {name="Speed", identifier="6", tags=[["datatype", "Int32"]]}
+      ]
+"#;
+
+    fn import(name: &str, content: &str) -> ImportedConfig {
+        let path = std::env::temp_dir().join(format!("diff_{}_{}.conf", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let imported = import_config(&path.display().to_string()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        imported
+    }
+
+    #[test]
+    fn same_configs_have_no_differences() {
+        let old = import("same_old", CONFIG);
+        let new = import(
+            "same_new",
+            &CONFIG.replace("\n\n", "\n\n# only a comment\n"),
+        );
+        assert!(diff_configs(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn changed_settings_are_listed() {
+        let old = import("settings_old", CONFIG);
+        let new = import(
+            "settings_new",
+            &CONFIG
+                .replace("192.168.0.20", "192.168.0.21")
+                .replace("token = \"secret\"", "token = \"other\"")
+                .replace("bucket = \"machines\"", "bucket = \"press\""),
+        );
+        assert_eq!(
+            diff_configs(&old, &new),
+            [
+                "~ OPC UA server: \"192.168.0.20\" -> \"192.168.0.21\"",
+                "~ output http://127.0.0.1:8086 bucket: \"machines\" -> \"press\"",
+                "~ output http://127.0.0.1:8086 token changed",
+            ]
+        );
+    }
+
+    #[test]
+    fn nodes_are_matched_by_identifier() {
+        let old = import("nodes_old", CONFIG);
+        let new = import(
+            "nodes_new",
+            &CONFIG
+                .replace("name=\"Temp\"", "name=\"Oil_Temp\"")
+                .replace(
+                    "{name=\"Speed\", identifier=\"6\", tags=[[\"datatype\", \"Int32\"]]}",
+                    "{name=\"Pressure\", identifier=\"7\", tags=[[\"datatype\", \"Float\"]]}",
+                ),
+        );
+        assert_eq!(
+            diff_configs(&old, &new),
+            [
+                "- opcua group 'Press': node i=6 'Speed'",
+                "+ opcua group 'Press': node i=7 'Pressure'",
+                "~ opcua group 'Press', node i=5 'Oil_Temp' name: \"Temp\" -> \"Oil_Temp\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_are_matched_by_name() {
+        let old = import("groups_old", CONFIG);
+        let new = import(
+            "groups_new",
+            &CONFIG.replace("name = \"Press\"", "name = \"Robot\""),
+        );
+        assert_eq!(
+            diff_configs(&old, &new),
+            [
+                "- opcua group 'Press' (2 nodes)",
+                "+ opcua group 'Robot' (2 nodes)",
+            ]
+        );
+    }
+}
@@ -1,21 +1,407 @@
+use regex::Regex;
 use roxmltree::Document;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::net::{IpAddr, Ipv6Addr};
 
-pub fn generate_config_content(influx_token: &str, config_strings: &[String]) -> String {
+/// Connection settings shared by every generated OPC UA input block.
+pub struct OpcSettings {
+    pub ip: String,
+    pub username: String,
+    pub password: String,
+    pub application_name: Option<String>,
+    pub product_uri: Option<String>,
+    pub auth: AuthMethod,
+    /// Certificate and private key paths on the IOT-2050 that secure the channel when the auth
+    /// method doesn't bring its own, Telegraf generates a self-signed pair when unset.
+    pub client_certificate: Option<(String, String)>,
+    pub security_policy: String,
+    pub security_mode: String,
+    pub connect_timeout: String,
+    pub request_timeout: String,
+    /// Only used by listener inputs, which keep a session open between notifications.
+    pub session_timeout: String,
+    /// How often the server publishes the notifications of listener inputs, apart from how often
+    /// it samples. Telegraf's default applies when unset.
+    pub subscription_interval: Option<String>,
+}
+
+/// How Telegraf authenticates against the OPC UA server.
+pub enum AuthMethod {
+    UserName,
+    Anonymous,
+    Certificate {
+        certificate: String,
+        private_key: String,
+    },
+}
+
+/// Destination of one generated `[[outputs.influxdb_v2]]` block.
+pub struct InfluxOutput {
+    pub url: String,
+    pub token: String,
+    pub organization: String,
+    pub bucket: String,
+    /// Routes metrics carrying a `bucket` tag to that bucket, the others go to `bucket`.
+    pub bucket_tag: bool,
+}
+
+/// Collection and flush settings of the generated `[agent]` block.
+pub struct AgentSettings {
+    pub interval: String,
+    pub flush_interval: String,
+    pub metric_batch_size: u32,
+    pub metric_buffer_limit: u32,
+}
+
+/// Accepts Telegraf durations like `10s`, `500ms` or `1h30m`: numbers each followed by a unit.
+pub fn is_valid_duration(duration: &str) -> bool {
+    duration_seconds(duration).is_some()
+}
+
+/// The length of a Telegraf duration in seconds, None when it isn't one.
+pub fn duration_seconds(duration: &str) -> Option<f64> {
+    let mut rest = duration;
+    if rest.is_empty() {
+        return None;
+    }
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        // Longest units first so "ms" isn't read as "m" followed by junk
+        let (unit, factor) = [
+            ("ns", 1e-9),
+            ("us", 1e-6),
+            ("µs", 1e-6),
+            ("ms", 1e-3),
+            ("s", 1.0),
+            ("m", 60.0),
+            ("h", 3600.0),
+        ]
+        .into_iter()
+        .find(|(unit, _)| rest.starts_with(unit))?;
+        rest = &rest[unit.len()..];
+        seconds += number * factor;
+    }
+    Some(seconds)
+}
+
+/// Accepts IPv4 and IPv6 addresses, the latter optionally in brackets, and DNS hostnames.
+pub fn is_valid_host(host: &str) -> bool {
+    if let Some(address) = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        return address.parse::<Ipv6Addr>().is_ok();
+    }
+    if host.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    // Dotted numbers that didn't parse as an address are a typo like 192.168.0.300, not a name
+    let numeric = |label: &str| label.chars().all(|c| c.is_ascii_digit());
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    host.len() <= 253 && !host.split('.').all(numeric) && host.split('.').all(valid_label)
+}
+
+/// Splits `host:port`, where an IPv6 host is bracketed as in `[fe80::1]:22`.
+pub fn split_host_port(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    // An unbracketed IPv6 address has colons of its own and can't carry a port
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return None;
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 && is_valid_host(host) => Some((host, port)),
+        _ => None,
+    }
+}
+
+/// The `opc.tcp://` endpoint of the OPC UA server, IPv6 addresses bracketed so their colons aren't read as the port.
+pub fn endpoint_url(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("opc.tcp://[{}]:4840", host)
+    } else {
+        format!("opc.tcp://{}:4840", host)
+    }
+}
+
+/// Accepts `http://` and `https://` URLs with a host and an optional numeric port.
+pub fn is_valid_http_url(url: &str) -> bool {
+    let rest = match url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let (host, port) = match authority.rsplit_once(':') {
+        // A bracketed IPv6 host contains colons of its own
+        Some((host, port)) if !port.contains(']') => (host, Some(port)),
+        _ => (authority, None),
+    };
+    !host.is_empty()
+        && !url.chars().any(char::is_whitespace)
+        && port.is_none_or(|port| port.parse::<u16>().is_ok())
+}
+
+/// A single node entry of a generated group.
+pub struct Node {
+    pub name: String,
+    pub identifier: String,
+    /// `i` for numeric identifiers, `s`, `g` or `b` for string, GUID and opaque ones.
+    pub identifier_type: String,
+    /// Per-node tags taken from `key=value` segments of the VariableMapping.
+    pub tags: Vec<(String, String)>,
+    /// Only reports changes beyond this deadband, listener inputs only.
+    pub deadband: Option<Deadband>,
+    /// Samples the node at this interval instead of the group's, listener inputs only.
+    pub sampling_interval: Option<String>,
+    /// The exported Description, written as a comment above the node.
+    pub description: Option<String>,
+}
+
+/// A data change filter of a monitored node.
+#[derive(Clone)]
+pub struct Deadband {
+    /// Either `Absolute` or `Percent` of the node's EURange.
+    pub kind: String,
+    pub value: f64,
+}
+
+impl Node {
+    /// Whether the node's datatype tag names a number, the only kind a deadband applies to.
+    pub fn is_numeric(&self) -> bool {
+        self.tags.iter().any(|(key, value)| {
+            key == "datatype" && BUILT_IN_TYPES[1..11].contains(&value.as_str())
+        })
+    }
+}
+
+/// An OPC UA group parsed from one XML file, rendered as one input block.
+pub struct OpcGroup {
+    pub name: String,
+    pub namespace: String,
+    pub interval: String,
+    pub is_listener: bool,
+    pub nodes: Vec<Node>,
+    /// Overrides the input-level `name`, which Telegraf uses as the measurement.
+    pub measurement: Option<String>,
+    /// The name as exported, kept as a `display_name` tag once `name` is sanitized.
+    pub display_name: Option<String>,
+    /// The XML file or imported config the group was read from.
+    pub source: String,
+    /// Whether values are stamped with the server's `source` time or Telegraf's `gather` time.
+    pub timestamp: String,
+    /// The InfluxDB bucket the group's metrics go to instead of the output's, through a `bucket` tag.
+    pub bucket: Option<String>,
+}
+
+impl OpcGroup {
+    /// The group's namespace, by index or, when a URI was given, as `namespace_uri`. Left out
+    /// when there is none, so Telegraf takes namespace 0 instead of failing on an empty one.
+    fn namespace_line(&self) -> String {
+        if self.namespace.is_empty() {
+            String::new()
+        } else if self.namespace.chars().all(|c| c.is_ascii_digit()) {
+            format!("\n      namespace = \"{}\"", self.namespace)
+        } else {
+            format!(
+                "\n      namespace_uri = \"{}\"",
+                escape_toml(&self.namespace)
+            )
+        }
+    }
+
+    /// Group-level default tags, only emitted when a display name or bucket is known.
+    fn default_tags_line(&self) -> String {
+        let mut tags = Vec::new();
+        if let Some(display_name) = &self.display_name {
+            tags.push(format!("display_name = \"{}\"", escape_toml(display_name)));
+        }
+        if let Some(bucket) = &self.bucket {
+            tags.push(format!("{} = \"{}\"", BUCKET_TAG, escape_toml(bucket)));
+        }
+        if tags.is_empty() {
+            return String::new();
+        }
+        format!("\n      default_tags = {{{}}}", tags.join(", "))
+    }
+}
+
+impl OpcSettings {
+    /// Client identity lines, only emitted for the fields that were provided.
+    fn identity_lines(&self) -> String {
+        let mut lines = String::new();
+        if let Some(application_name) = &self.application_name {
+            lines.push_str(&format!(
+                "\napplication_name = \"{}\"",
+                escape_toml(application_name)
+            ));
+        }
+        if let Some(product_uri) = &self.product_uri {
+            lines.push_str(&format!("\nproduct_uri = \"{}\"", escape_toml(product_uri)));
+        }
+        lines
+    }
+
+    /// Certificate, client identity and authentication lines for the chosen auth method.
+    fn auth_lines(&self) -> String {
+        let (certificate, private_key) = match &self.auth {
+            AuthMethod::Certificate {
+                certificate,
+                private_key,
+            } => (certificate.as_str(), private_key.as_str()),
+            _ => self
+                .client_certificate
+                .as_ref()
+                .map(|(certificate, private_key)| (certificate.as_str(), private_key.as_str()))
+                .unwrap_or(("", "")),
+        };
+        let mut lines = format!(
+            "certificate = \"{}\"\nprivate_key = \"{}\"{}",
+            escape_toml(certificate),
+            escape_toml(private_key),
+            self.identity_lines()
+        );
+        match self.auth {
+            AuthMethod::UserName => lines.push_str(&format!(
+                "\nauth_method = \"UserName\"\nusername = \"{}\"\npassword = \"{}\"",
+                escape_toml(&self.username),
+                escape_toml(&self.password)
+            )),
+            AuthMethod::Anonymous => lines.push_str("\nauth_method = \"Anonymous\""),
+            AuthMethod::Certificate { .. } => lines.push_str("\nauth_method = \"Certificate\""),
+        }
+        lines
+    }
+}
+
+/// Opens the machine-managed part of a config, followed by the tool version and generation time.
+/// Everything from here up to END_MARKER is replaced on --merge.
+pub const BEGIN_MARKER: &str = "# >>> BEGIN generated by iot2050-telegraf-config";
+pub const END_MARKER: &str = "# <<< END generated";
+
+pub fn generated_section(config_strings: &[String]) -> String {
+    // The OPC UA inputs between the markers, the only part of a config --merge touches
     format!(
-        r#"# Global tags can be specified here in key="value" format.
-[global_tags]
+        "{} v{} at {}\n{}\n{}",
+        BEGIN_MARKER,
+        env!("CARGO_PKG_VERSION"),
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        config_strings.join("\n\n"),
+        END_MARKER
+    )
+}
+
+/// Escapes a value for use inside a basic (double-quoted) TOML string.
+pub fn escape_toml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn validate_config(content: &str) -> Result<(), String> {
+    // Only checks that the config is valid TOML, Telegraf's own rules are left to telegraf --test
+    let error = match toml::from_str::<toml::Table>(content) {
+        Ok(_) => return Ok(()),
+        Err(error) => error,
+    };
+    let position = error.span().map(|span| {
+        let before = &content[..span.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+        (line, column)
+    });
+    Err(match position {
+        Some((line, column)) => format!(
+            "line {}, column {}: {}\n  {}",
+            line,
+            column,
+            error.message(),
+            content.lines().nth(line - 1).unwrap_or_default()
+        ),
+        None => error.message().to_string(),
+    })
+}
+
+/// The group tag naming a bucket other than the output's, see `OpcGroup::bucket`.
+pub const BUCKET_TAG: &str = "bucket";
+
+fn format_influx_output(influx: &InfluxOutput) -> String {
+    let bucket_tag = if influx.bucket_tag {
+        format!(
+            "  bucket_tag = \"{}\"\n  exclude_bucket_tag = true\n",
+            BUCKET_TAG
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        r#"# Configuration for sending metrics to InfluxDB 2.0
+[[outputs.influxdb_v2]]
+  urls = ["{}"]
+  token = "{}"
+  organization = "{}"
+  bucket = "{}"
+{}"#,
+        escape_toml(&influx.url),
+        escape_toml(&influx.token),
+        escape_toml(&influx.organization),
+        escape_toml(&influx.bucket),
+        bucket_tag
+    )
+}
+
+fn format_prometheus_output(url: &str) -> String {
+    format!(
+        r#"# Configuration for sending metrics to Prometheus over remote write
+[[outputs.http]]
+  url = "{}"
+  data_format = "prometheusremotewrite"
+  [outputs.http.headers]
+    Content-Type = "application/x-protobuf"
+    Content-Encoding = "snappy"
+    X-Prometheus-Remote-Write-Version = "0.1.0"
+"#,
+        escape_toml(url)
+    )
+}
 
+/// The built-in layout of a generated config, `--template` replaces it.
+pub const DEFAULT_TEMPLATE: &str = r#"# Global tags can be specified here in key="value" format.
+[global_tags]
+{{global_tags}}
 # Configuration for telegraf agent
 [agent]
   ## Default data collection interval for all inputs
-  interval = "1000ms"
+  interval = "{{agent_interval}}"
   round_interval = true
 
-  metric_batch_size = 10000
-  metric_buffer_limit = 100000
+  metric_batch_size = {{metric_batch_size}}
+  metric_buffer_limit = {{metric_buffer_limit}}
 
   collection_jitter = "0s"
-  flush_interval = "10s"
+  flush_interval = "{{flush_interval}}"
   flush_jitter = "0s"
   precision = "0s"
 
@@ -32,163 +418,1202 @@ pub fn generate_config_content(influx_token: &str, config_strings: &[String]) ->
   hostname = ""
   omit_hostname = false
 
-# Configuration for sending metrics to InfluxDB 2.0
-[[outputs.influxdb_v2]]
-  urls = ["http://127.0.0.1:8086"]
-  token = "{}"
-  organization = "org"
-  bucket = "line"
+{{influx_outputs}}
+{{opcua_inputs}}
+"#;
 
-{}
-"#,
-        influx_token,
-        config_strings.join("\n\n")
-    )
+fn render_template(template: &str, values: &[(&str, String)]) -> Result<String, String> {
+    // Replaces every {{name}} in one pass, so filled in values are never expanded again
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or("unclosed {{ in the template")?;
+        let name = after[..end].trim();
+        let value = values
+            .iter()
+            .find(|(placeholder, _)| *placeholder == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| {
+                format!(
+                    "unknown placeholder {{{{{}}}}}, expecting one of: {}",
+                    name,
+                    values
+                        .iter()
+                        .map(|(placeholder, _)| *placeholder)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+        rendered.push_str(value);
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
 }
 
-fn format_standard_config(
-    ip: &str,
-    username: &str,
-    password: &str,
-    group_name: &str,
-    namespace_number: &str,
-    interval: &str,
-    nodes_str: &str,
-) -> String {
+/// Fills the template in. With a `prometheus_url` the outputs go to Prometheus instead of the InfluxDBs.
+pub fn generate_config_content(
+    template: &str,
+    global_tags: &[(String, String)],
+    agent: &AgentSettings,
+    outputs: &[InfluxOutput],
+    prometheus_url: Option<&str>,
+    config_strings: &[String],
+) -> Result<String, String> {
+    // Without the inputs there'd be nothing to generate, and --merge would have no markers to find
+    if !template.contains("{{opcua_inputs}}") {
+        return Err("the template has no {{opcua_inputs}} placeholder".to_string());
+    }
+    // A single sink can also be placed field by field, with several only the whole blocks make sense
+    let first = outputs.first();
+    let field = |value: fn(&InfluxOutput) -> &String| {
+        first
+            .map(|output| escape_toml(value(output)))
+            .unwrap_or_default()
+    };
+    let values = [
+        (
+            "global_tags",
+            global_tags
+                .iter()
+                .map(|(key, value)| format!("  {} = \"{}\"\n", key, escape_toml(value)))
+                .collect(),
+        ),
+        ("agent_interval", agent.interval.clone()),
+        ("flush_interval", agent.flush_interval.clone()),
+        ("metric_batch_size", agent.metric_batch_size.to_string()),
+        ("metric_buffer_limit", agent.metric_buffer_limit.to_string()),
+        (
+            "influx_outputs",
+            match prometheus_url {
+                Some(url) => format_prometheus_output(url),
+                None => outputs
+                    .iter()
+                    .map(format_influx_output)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            },
+        ),
+        ("influx_url", field(|output| &output.url)),
+        ("influx_token", field(|output| &output.token)),
+        ("influx_org", field(|output| &output.organization)),
+        ("influx_bucket", field(|output| &output.bucket)),
+        ("opcua_inputs", generated_section(config_strings)),
+    ];
+    render_template(template, &values)
+}
+
+fn format_standard_input(opc: &OpcSettings, group: &OpcGroup) -> String {
+    let measurement = group.measurement.as_deref().unwrap_or("opcua");
     format!(
         r#"
 [[inputs.opcua]]
-name = "opcua"
+name = "{}"
 interval = "{}"
-endpoint = "opc.tcp://{}:4840"
-connect_timeout = "30s"
-request_timeout = "10s"
-security_policy = "Basic256Sha256"
-security_mode = "SignAndEncrypt"
-certificate = ""
-private_key = ""
-auth_method = "UserName"
-username = "{}"
-password = "{}"
-timestamp = "source"
-client_trace = false
+endpoint = "{}"
+connect_timeout = "{}"
+request_timeout = "{}"
+security_policy = "{}"
+security_mode = "{}"
+{}
+timestamp = "{}"
+client_trace = false"#,
+        escape_toml(measurement),
+        group.interval,
+        endpoint_url(&opc.ip),
+        escape_toml(&opc.connect_timeout),
+        escape_toml(&opc.request_timeout),
+        opc.security_policy,
+        opc.security_mode,
+        opc.auth_lines(),
+        escape_toml(&group.timestamp),
+    )
+}
+
+fn format_standard_group(group: &OpcGroup, nodes_str: &str) -> String {
+    format!(
+        r#"
     [[inputs.opcua.group]]
-      name = "{}"
-      namespace = "{}"
+      name = "{}"{}{}
       identifier_type = "i"
       nodes = [
         {}
-      ]
-    "#,
-        interval, ip, username, password, group_name, namespace_number, nodes_str
+      ]"#,
+        escape_toml(&group.name),
+        group.default_tags_line(),
+        group.namespace_line(),
+        nodes_str
     )
 }
 
-fn format_listener_config(
-    ip: &str,
-    username: &str,
-    password: &str,
-    group_name: &str,
-    namespace_number: &str,
-    interval: &str,
-    nodes_str: &str,
-) -> String {
+fn format_listener_input(opc: &OpcSettings, group: &OpcGroup) -> String {
+    let measurement = group.measurement.as_deref().unwrap_or("opcua_listener");
     format!(
         r#"
 [[inputs.opcua_listener]]
-name = "opcua_listener"
-endpoint = "opc.tcp://{}:4840"
+name = "{}"
+endpoint = "{}"
 connect_fail_behavior = "ignore"
-connect_timeout = "30s"
-request_timeout = "10s"
-session_timeout = "20m"
-security_policy = "Basic256Sha256"
-security_mode = "SignAndEncrypt"
-certificate = ""
-private_key = ""
-auth_method = "UserName"
-username = "{}"
-password = "{}"
-timestamp = "source"
-client_trace = false
+connect_timeout = "{}"
+request_timeout = "{}"
+session_timeout = "{}"{}
+security_policy = "{}"
+security_mode = "{}"
+{}
+timestamp = "{}"
+client_trace = false"#,
+        escape_toml(measurement),
+        endpoint_url(&opc.ip),
+        escape_toml(&opc.connect_timeout),
+        escape_toml(&opc.request_timeout),
+        escape_toml(&opc.session_timeout),
+        opc.subscription_interval
+            .as_ref()
+            .map(|interval| format!("\nsubscription_interval = \"{}\"", escape_toml(interval)))
+            .unwrap_or_default(),
+        opc.security_policy,
+        opc.security_mode,
+        opc.auth_lines(),
+        escape_toml(&group.timestamp),
+    )
+}
+
+fn format_listener_group(group: &OpcGroup, nodes_str: &str) -> String {
+    format!(
+        r#"
     [[inputs.opcua_listener.group]]
-      name = "{}"
-      sampling_interval = "{}"
-      namespace = "{}"
+      name = "{}"{}
+      sampling_interval = "{}"{}
       identifier_type = "i"
       nodes = [
         {}
-      ]
-    "#,
-        ip, username, password, group_name, interval, namespace_number, nodes_str
+      ]"#,
+        escape_toml(&group.name),
+        group.default_tags_line(),
+        group.interval,
+        group.namespace_line(),
+        nodes_str
     )
 }
 
+fn format_nodes(group: &OpcGroup) -> String {
+    group
+        .nodes
+        .iter()
+        .map(|node| {
+            let tags = if node.tags.is_empty() {
+                String::new()
+            } else {
+                let pairs = node
+                    .tags
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("[\"{}\", \"{}\"]", escape_toml(key), escape_toml(value))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(", tags=[{}]", pairs)
+            };
+            // The group defaults to numeric identifiers, other types are set per node
+            let identifier_type = if node.identifier_type == "i" {
+                String::new()
+            } else {
+                format!(", identifier_type=\"{}\"", node.identifier_type)
+            };
+            // Polled inputs read every interval, only subscriptions can sample or filter per node
+            let mut params = Vec::new();
+            if group.is_listener {
+                if let Some(sampling_interval) = &node.sampling_interval {
+                    params.push(format!(
+                        "sampling_interval=\"{}\"",
+                        escape_toml(sampling_interval)
+                    ));
+                }
+                if let Some(deadband) = &node.deadband {
+                    params.push(format!(
+                        "data_change_filter={{trigger=\"StatusValue\", deadband_type=\"{}\", deadband_value={:?}}}",
+                        deadband.kind, deadband.value
+                    ));
+                }
+            }
+            let monitoring = if params.is_empty() {
+                String::new()
+            } else {
+                format!(", monitoring_params={{{}}}", params.join(", "))
+            };
+            // TOML allows comments between array values, a line break in one would end it early
+            let comment = match &node.description {
+                Some(description) => format!("# {}\n        ", description),
+                None => String::new(),
+            };
+            format!(
+                "{}{{name=\"{}\", identifier=\"{}\"{}{}{}}}",
+                comment,
+                escape_toml(&node.name),
+                escape_toml(&node.identifier),
+                identifier_type,
+                tags,
+                monitoring
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n        ")
+}
+
+/// Renders input blocks for the groups, one per group except that consecutive groups read
+/// from the same file with the same input settings share a block.
+pub fn format_inputs(opc: &OpcSettings, groups: &[OpcGroup]) -> Vec<String> {
+    let same_input = |a: &OpcGroup, b: &OpcGroup| {
+        a.source == b.source
+            && a.is_listener == b.is_listener
+            && a.measurement == b.measurement
+            && a.timestamp == b.timestamp
+            && (a.is_listener || a.interval == b.interval)
+    };
+    groups
+        .chunk_by(|a, b| same_input(a, b))
+        .map(|input| {
+            let first = &input[0];
+            let mut block = if first.is_listener {
+                format_listener_input(opc, first)
+            } else {
+                format_standard_input(opc, first)
+            };
+            for group in input {
+                let nodes_str = format_nodes(group);
+                block.push_str(&if group.is_listener {
+                    format_listener_group(group, &nodes_str)
+                } else {
+                    format_standard_group(group, &nodes_str)
+                });
+            }
+            block.push_str("\n    ");
+            block
+        })
+        .collect()
+}
+
+/// The parts of an `ns=<index>;<type>=<identifier>` NodeId.
+pub struct NodeId {
+    pub namespace: String,
+    pub identifier_type: String,
+    /// Everything after the type, string identifiers may contain `=`, `;` and spaces.
+    pub identifier: String,
+}
+
+/// Parses a NodeId, a missing `ns=` meaning namespace 0. Returns None for malformed ones.
+pub fn parse_node_id(node_id: &str) -> Option<NodeId> {
+    // Only the first `;` and the first `=` after the namespace separate parts
+    let (namespace, rest) = match node_id.strip_prefix("ns=") {
+        Some(rest) => rest.split_once(';')?,
+        None => ("0", node_id),
+    };
+    let (identifier_type, identifier) = rest.split_once('=')?;
+    let numeric = |value: &str| !value.is_empty() && value.chars().all(|c| c.is_ascii_digit());
+    if !numeric(namespace)
+        || !["i", "s", "g", "b"].contains(&identifier_type)
+        || identifier.is_empty()
+        || (identifier_type == "i" && !numeric(identifier))
+    {
+        return None;
+    }
+    Some(NodeId {
+        namespace: namespace.to_string(),
+        identifier_type: identifier_type.to_string(),
+        identifier: identifier.to_string(),
+    })
+}
+
+/// Reads a deadband kind as given on the command line or in a VariableMapping.
+pub fn parse_deadband_type(kind: &str) -> Option<&'static str> {
+    match kind.to_ascii_lowercase().as_str() {
+        "absolute" => Some("Absolute"),
+        "percent" => Some("Percent"),
+        _ => None,
+    }
+}
+
+fn take_deadband(tags: &mut Vec<(String, String)>, node_id: &str) -> Option<Deadband> {
+    // `deadband` and `deadband_type` segments of a VariableMapping configure the node instead of tagging it
+    let mut take = |key: &str| {
+        let position = tags.iter().position(|(tag, _)| tag == key)?;
+        Some(tags.remove(position).1)
+    };
+    let value = take("deadband");
+    let kind = take("deadband_type");
+    let value = value?;
+    let value = match value.parse::<f64>() {
+        Ok(value) if value >= 0.0 => value,
+        _ => {
+            println!(
+                "Warning: invalid deadband '{}' for NodeId '{}', expecting a number, no deadband set",
+                value, node_id
+            );
+            return None;
+        }
+    };
+    let kind = match kind.as_deref().map(parse_deadband_type) {
+        None => "Absolute",
+        Some(Some(kind)) => kind,
+        Some(None) => {
+            println!(
+                "Warning: invalid deadband_type for NodeId '{}', expecting absolute or percent, no deadband set",
+                node_id
+            );
+            return None;
+        }
+    };
+    Some(Deadband {
+        kind: kind.to_string(),
+        value,
+    })
+}
+
+fn take_sampling_interval(tags: &mut Vec<(String, String)>, node_id: &str) -> Option<String> {
+    // A `sampling_interval` segment of a VariableMapping overrides the group's for this node
+    let position = tags
+        .iter()
+        .position(|(tag, _)| tag == "sampling_interval")?;
+    let (_, interval) = tags.remove(position);
+    if !is_valid_duration(&interval) {
+        println!(
+            "Warning: invalid sampling_interval '{}' for NodeId '{}', expecting something like 100ms, using the group's",
+            interval, node_id
+        );
+        return None;
+    }
+    Some(interval)
+}
+
+fn take_field_name(tags: &mut Vec<(String, String)>) -> Option<String> {
+    // A `field` segment of a VariableMapping names the InfluxDB field, winning over a bare name
+    let position = tags.iter().position(|(tag, _)| tag == "field")?;
+    Some(tags.remove(position).1).filter(|field| !field.is_empty())
+}
+
+/// Renames the nodes whose name is a key of `fields` to its value, so the same signal of
+/// different machines is written to one field. Returns how many nodes were renamed.
+pub fn rename_fields(groups: &mut [OpcGroup], fields: &HashMap<String, String>) -> usize {
+    let mut renamed = 0;
+    for group in groups.iter_mut() {
+        let mut new_names = Vec::new();
+        for node in &mut group.nodes {
+            if let Some(field) = fields.get(&node.name) {
+                log::trace!("Node {} renamed to '{}'", node.name, field);
+                node.name = field.clone();
+                new_names.push(field.as_str());
+                renamed += 1;
+            }
+        }
+        // Telegraf would write both into one field of the same series
+        new_names.sort_unstable();
+        new_names.dedup();
+        for name in new_names {
+            if group.nodes.iter().filter(|node| node.name == name).count() < 2 {
+                continue;
+            }
+            println!(
+                "Warning: group '{}' from {} has several nodes named '{}', they are written to the same field.",
+                group.name, group.source, name
+            );
+        }
+    }
+    renamed
+}
+
+/// Splits a VariableMapping into an optional node name and `key=value` tags.
+///
+/// Segments are separated by `;`. A segment without `=` renames the node, every other
+/// segment becomes a tag. Double quotes group text so a quoted value may contain `;` or
+/// `=`, the quotes themselves are dropped, e.g. `Temp;area=press;note="a;b"`.
+pub fn parse_variable_mapping(mapping: &str) -> (Option<String>, Vec<(String, String)>) {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    // Track where the first unquoted '=' of each segment sits, quoted ones are literal
+    let mut split_at = None;
+    for c in mapping.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                segments.push((std::mem::take(&mut current), split_at.take()));
+            }
+            '=' if !in_quotes && split_at.is_none() => {
+                split_at = Some(current.len());
+                current.push(c);
+            }
+            _ => current.push(c),
+        }
+    }
+    segments.push((current, split_at));
+
+    let mut name = None;
+    let mut tags = Vec::new();
+    for (segment, split_at) in segments {
+        match split_at {
+            Some(index) => {
+                let key = segment[..index].trim();
+                let value = segment[index + 1..].trim();
+                if !key.is_empty() {
+                    tags.push((key.to_string(), value.to_string()));
+                }
+            }
+            None if !segment.trim().is_empty() => name = Some(segment.trim().to_string()),
+            None => {}
+        }
+    }
+    (name, tags)
+}
+
+/// OPC UA built-in data types in NodeId order, `i=1` is Boolean.
+const BUILT_IN_TYPES: [&str; 25] = [
+    "Boolean",
+    "SByte",
+    "Byte",
+    "Int16",
+    "UInt16",
+    "Int32",
+    "UInt32",
+    "Int64",
+    "UInt64",
+    "Float",
+    "Double",
+    "String",
+    "DateTime",
+    "Guid",
+    "ByteString",
+    "XmlElement",
+    "NodeId",
+    "ExpandedNodeId",
+    "StatusCode",
+    "QualifiedName",
+    "LocalizedText",
+    "Structure",
+    "DataValue",
+    "BaseDataType",
+    "DiagnosticInfo",
+];
+
+/// Maps a UAVariable DataType, given as `i=11` or as an alias like `Double`, to its type name.
+pub fn data_type_name(data_type: &str) -> Option<&'static str> {
+    let number = data_type
+        .strip_prefix("ns=0;i=")
+        .or_else(|| data_type.strip_prefix("i="));
+    match number {
+        Some(number) => number
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| number.checked_sub(1))
+            .and_then(|index| BUILT_IN_TYPES.get(index).copied()),
+        None => BUILT_IN_TYPES
+            .iter()
+            .find(|name| **name == data_type)
+            .copied(),
+    }
+}
+
+/// Turns a group name into a measurement name made of ASCII letters, digits and underscores.
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    sanitized.trim_matches('_').to_string()
+}
+
+fn unique_name(base: &str, used: &mut Vec<String>) -> String {
+    // Appends _2, _3, .. until the name hasn't been handed out yet
+    let mut name = base.to_string();
+    let mut suffix = 2;
+    while used.contains(&name) {
+        name = format!("{}_{}", base, suffix);
+        suffix += 1;
+    }
+    used.push(name.clone());
+    name
+}
+
+/// Gives every group its own measurement, named after the group and made unique with a numeric suffix.
+pub fn assign_measurements(groups: &mut [OpcGroup]) {
+    let mut used: Vec<String> = Vec::new();
+    for group in groups.iter_mut() {
+        let mut base = sanitize_name(&group.name);
+        if base.is_empty() {
+            base = "opcua".to_string();
+        }
+        group.measurement = Some(unique_name(&base, &mut used));
+    }
+}
+
+/// A group whose sanitized name was already taken by an earlier group, groups given by position.
+pub struct DuplicateGroupName {
+    pub name: String,
+    pub first_group: usize,
+    pub group: usize,
+}
+
+/// Replaces group names with sanitized ones, keeping the original as the display name.
+/// Names that collide are reported, and only made unique with a numeric suffix when `rename` is set.
+pub fn sanitize_group_names(groups: &mut [OpcGroup], rename: bool) -> Vec<DuplicateGroupName> {
+    let mut used: Vec<String> = Vec::new();
+    let mut duplicates = Vec::new();
+    for (index, group) in groups.iter_mut().enumerate() {
+        if group.display_name.is_none() {
+            group.display_name = Some(group.name.clone());
+        }
+        let mut base = sanitize_name(&group.name);
+        if base.is_empty() {
+            base = "group".to_string();
+        }
+        if let Some(first_group) = used.iter().position(|name| *name == base) {
+            duplicates.push(DuplicateGroupName {
+                name: base.clone(),
+                first_group,
+                group: index,
+            });
+        }
+        group.name = if rename {
+            unique_name(&base, &mut used)
+        } else {
+            used.push(base.clone());
+            base
+        };
+    }
+    duplicates
+}
+
+/// A node whose `ns=;i=` pair was already used by an earlier group, groups given by position.
+pub struct DuplicateNode {
+    pub namespace: String,
+    pub identifier: String,
+    pub identifier_type: String,
+    pub first_group: usize,
+    pub group: usize,
+}
+
+/// Finds nodes that repeat an earlier group's NodeId, dropping the repeats when `remove` is set.
+pub fn find_duplicate_nodes(groups: &mut [OpcGroup], remove: bool) -> Vec<DuplicateNode> {
+    let mut seen: Vec<(String, String, String, usize)> = Vec::new();
+    let mut duplicates = Vec::new();
+    for (index, group) in groups.iter_mut().enumerate() {
+        let mut kept = Vec::new();
+        for node in group.nodes.drain(..) {
+            let first_group = seen
+                .iter()
+                .find(|(namespace, identifier_type, identifier, _)| {
+                    *namespace == group.namespace
+                        && *identifier_type == node.identifier_type
+                        && *identifier == node.identifier
+                })
+                .map(|(_, _, _, first_group)| *first_group);
+            match first_group {
+                Some(first_group) => {
+                    duplicates.push(DuplicateNode {
+                        namespace: group.namespace.clone(),
+                        identifier: node.identifier.clone(),
+                        identifier_type: node.identifier_type.clone(),
+                        first_group,
+                        group: index,
+                    });
+                    if !remove {
+                        kept.push(node);
+                    }
+                }
+                None => {
+                    seen.push((
+                        group.namespace.clone(),
+                        node.identifier_type.clone(),
+                        node.identifier.clone(),
+                        index,
+                    ));
+                    kept.push(node);
+                }
+            }
+        }
+        group.nodes = kept;
+    }
+    duplicates
+}
+
+/// Prompt answers that are known without asking: given with a trailing `*` for every later file,
+/// or set ahead for single files.
+#[derive(Default)]
+pub struct SharedAnswers {
+    answers: Vec<(String, String)>,
+    /// Answers given ahead for single files, by file name, setting and answer.
+    presets: Vec<(String, String, String)>,
+}
+
+impl SharedAnswers {
+    /// Answers a setting for the XML file named `file_name` without asking, ahead of shared answers.
+    /// A later preset for the same file and setting replaces the earlier one.
+    pub fn preset(&mut self, file_name: &str, setting: &str, answer: &str) {
+        self.presets
+            .retain(|(file, preset, _)| !(file == file_name && preset == setting));
+        self.presets.push((
+            file_name.to_string(),
+            setting.to_string(),
+            answer.to_string(),
+        ));
+    }
+
+    /// Answers a setting for every XML file without asking. Presets for single files still win.
+    pub fn answer_all(&mut self, setting: &str, answer: &str) {
+        self.answers.push((setting.to_string(), answer.to_string()));
+    }
+
+    fn get(&self, xml_file: &str, setting: &str) -> Option<String> {
+        let file_name = std::path::Path::new(xml_file)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.presets
+            .iter()
+            .find(|(file, preset, _)| *file == file_name && preset == setting)
+            .map(|(_, _, answer)| answer.clone())
+            .or_else(|| {
+                self.answers
+                    .iter()
+                    .find(|(shared, _)| shared == setting)
+                    .map(|(_, answer)| answer.clone())
+            })
+    }
+
+    /// Keeps an answer for the remaining files and says which files it applies to.
+    fn share(&mut self, setting: &str, answer: &str, remaining: &[String]) {
+        if remaining.is_empty() {
+            return;
+        }
+        // An empty namespace answer stands for whatever is detected in each file
+        let shown = if answer.is_empty() {
+            "the detected value".to_string()
+        } else {
+            format!("'{}'", answer)
+        };
+        say!(
+            "Using {} as the {} for the remaining files: {}",
+            shown,
+            setting,
+            remaining.join(", ")
+        );
+        self.answers.push((setting.to_string(), answer.to_string()));
+    }
+}
+
+fn reuse_hint(remaining: &[String]) -> &'static str {
+    if remaining.is_empty() {
+        ""
+    } else {
+        ", end with * to use it for all remaining files"
+    }
+}
+
+fn strip_reuse(answer: &str) -> (&str, bool) {
+    // A trailing * marks an answer for every remaining file
+    match answer.strip_suffix('*') {
+        Some(answer) => (answer.trim(), true),
+        None => (answer, false),
+    }
+}
+
+fn prompt_interval(
+    setting: &str,
+    remaining: &[String],
+) -> Result<(String, bool), Box<dyn std::error::Error>> {
+    // Asks until the answer is a Telegraf duration, piped input gets one try
+    let interactive = std::io::stdin().is_terminal();
+    say!(
+        "----Enter the {} in ms (default 1000ms{}):",
+        setting,
+        reuse_hint(remaining)
+    );
+    loop {
+        let mut interval = String::new();
+        let read = std::io::stdin().read_line(&mut interval)?;
+        let (interval, reuse) = strip_reuse(interval.trim());
+        if interval.is_empty() {
+            return Ok(("1000ms".to_string(), reuse));
+        }
+        if is_valid_duration(interval) {
+            return Ok((interval.to_string(), reuse));
+        }
+
+        // The prompt asks for ms, so a bare number most likely means milliseconds
+        if interval.chars().all(|c| c.is_ascii_digit()) {
+            if !interactive {
+                say!("Using {}ms for the {} '{}'.", interval, setting, interval);
+                return Ok((format!("{}ms", interval), reuse));
+            }
+            say!("Use {}ms? (Y/n)", interval);
+            let mut confirm = String::new();
+            std::io::stdin().read_line(&mut confirm)?;
+            if !confirm.trim().eq_ignore_ascii_case("n") {
+                return Ok((format!("{}ms", interval), reuse));
+            }
+        } else if !interactive || read == 0 {
+            return Err(format!(
+                "invalid {} '{}', expecting a duration like 500ms or 1s",
+                setting, interval
+            )
+            .into());
+        } else {
+            say!("'{}' is not a duration like 500ms or 1s.", interval);
+        }
+        say!("----Enter the {} again:", setting);
+    }
+}
+
+fn variable_namespaces(doc: &Document) -> Vec<usize> {
+    // The distinct namespaces the variables live in, leaving out the OPC UA namespace 0
+    let mut namespaces: Vec<usize> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("UAVariable"))
+        .filter_map(|n| parse_node_id(n.attribute("NodeId")?))
+        .filter_map(|node_id| node_id.namespace.parse().ok())
+        .filter(|namespace| *namespace != 0)
+        .collect();
+    namespaces.sort_unstable();
+    namespaces.dedup();
+    namespaces
+}
+
+fn detect_namespace(doc: &Document) -> Option<(String, String)> {
+    // The only namespace the variables use, or when they use several the one the NodeSet defines in <Models>
+    let uris: Vec<&str> = doc
+        .descendants()
+        .find(|n| n.has_tag_name("NamespaceUris"))
+        .map(|uris| {
+            uris.children()
+                .filter(|n| n.has_tag_name("Uri"))
+                .filter_map(|n| n.text())
+                .map(str::trim)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // NodeIds in the XML count from 1 into NamespaceUris, 0 being the OPC UA namespace itself
+    let namespaces = variable_namespaces(doc);
+    let model_uris: Vec<&str> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("Model"))
+        .filter_map(|n| n.attribute("ModelUri"))
+        .filter(|uri| uris.contains(uri))
+        .collect();
+    let index = match (namespaces.as_slice(), model_uris.as_slice()) {
+        ([namespace], _) => *namespace,
+        (_, [model_uri]) => uris.iter().position(|uri| uri == model_uri)? + 1,
+        _ => return None,
+    };
+    let uri = uris.get(index.checked_sub(1)?)?;
+    Some((index.to_string(), uri.to_string()))
+}
+
+fn array_dimensions(variable: roxmltree::Node) -> Option<String> {
+    // Scalars have a ValueRank of -1, the default, arrays one of 0 or more. Without ArrayDimensions
+    // the length of each dimension is unknown and shown as `?`, e.g. `?x?` for a matrix
+    let rank: i32 = variable.attribute("ValueRank")?.trim().parse().ok()?;
+    if rank < 0 {
+        return None;
+    }
+    let dimensions = match variable.attribute("ArrayDimensions") {
+        Some(dimensions) if !dimensions.trim().is_empty() => dimensions
+            .split(',')
+            .map(|length| match length.trim() {
+                "0" => "?",
+                length => length,
+            })
+            .collect::<Vec<_>>()
+            .join("x"),
+        _ => vec!["?"; rank.max(1) as usize].join("x"),
+    };
+    Some(dimensions)
+}
+
+/// Hierarchical references, by name and by NodeId, that make a node part of another one.
+const CHILD_REFERENCES: [&str; 10] = [
+    "HasComponent",
+    "HasProperty",
+    "Organizes",
+    "HasOrderedComponent",
+    "HasChild",
+    "i=47",
+    "i=46",
+    "i=35",
+    "i=49",
+    "i=34",
+];
+
+fn browse_paths(doc: &Document) -> HashMap<String, String> {
+    // The BrowseNames from the topmost node of the file down to each node, joined by `/`.
+    // Parents come from ParentNodeId, inverse references of the child or forward ones of the parent
+    let nodes: Vec<roxmltree::Node> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("UAObject") || n.has_tag_name("UAVariable"))
+        .filter(|n| n.attribute("NodeId").is_some())
+        .collect();
+    let mut names = HashMap::new();
+    let mut parents: HashMap<String, String> = HashMap::new();
+    for node in &nodes {
+        let node_id = node.attribute("NodeId").unwrap_or_default().to_string();
+        let browse_name = node.attribute("BrowseName").unwrap_or_default();
+        // Drops the namespace prefix of `2:Speed`
+        let browse_name = match browse_name.split_once(':') {
+            Some((index, name)) if index.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => browse_name,
+        };
+        names.insert(node_id.clone(), browse_name.to_string());
+        if let Some(parent) = node.attribute("ParentNodeId") {
+            parents.insert(node_id.clone(), parent.to_string());
+        }
+        for reference in node.descendants().filter(|n| n.has_tag_name("Reference")) {
+            let hierarchical = reference
+                .attribute("ReferenceType")
+                .is_some_and(|kind| CHILD_REFERENCES.contains(&kind));
+            let Some(target) = reference.text().map(str::trim).filter(|_| hierarchical) else {
+                continue;
+            };
+            if reference.attribute("IsForward") == Some("false") {
+                parents.entry(node_id.clone()).or_insert(target.to_string());
+            } else {
+                parents.entry(target.to_string()).or_insert(node_id.clone());
+            }
+        }
+    }
+
+    let mut paths = HashMap::new();
+    for node_id in names.keys() {
+        let mut path = vec![names[node_id].as_str()];
+        let mut current = node_id;
+        // Parents outside the file, like the Objects folder, end the path, as does a cycle
+        while let Some(parent) = parents
+            .get(current)
+            .filter(|parent| names.contains_key(*parent))
+        {
+            if path.len() > names.len() {
+                break;
+            }
+            path.push(&names[parent]);
+            current = parent;
+        }
+        path.reverse();
+        paths.insert(node_id.clone(), path.join("/"));
+    }
+    paths
+}
+
+/// A UAVariable as exported, for looking at a NodeSet before generating from it.
+pub struct ListedNode {
+    pub node_id: String,
+    pub browse_name: String,
+    pub variable_mapping: String,
+    /// The type name where the DataType is a built-in one, the DataType as given otherwise.
+    pub data_type: String,
+}
+
+/// Reads every UAVariable of an XML file, whatever its namespace, without asking anything.
+pub fn list_nodes(xml_file: &str) -> Result<Vec<ListedNode>, Box<dyn std::error::Error>> {
+    let xml = std::fs::read_to_string(xml_file)
+        .map_err(|e| format!("unable to read {}: {}", xml_file, e))?;
+    let doc = Document::parse(&xml).map_err(|e| format!("unable to parse {}: {}", xml_file, e))?;
+    Ok(doc
+        .descendants()
+        .filter(|n| n.has_tag_name("UAVariable"))
+        .map(|variable| {
+            let data_type = variable.attribute("DataType").unwrap_or_default();
+            ListedNode {
+                node_id: variable.attribute("NodeId").unwrap_or_default().to_string(),
+                browse_name: variable
+                    .attribute("BrowseName")
+                    .unwrap_or_default()
+                    .to_string(),
+                variable_mapping: variable
+                    .descendants()
+                    .find(|n| n.has_tag_name("VariableMapping"))
+                    .and_then(|n| n.text())
+                    .unwrap_or_default()
+                    .to_string(),
+                data_type: data_type_name(data_type).unwrap_or(data_type).to_string(),
+            }
+        })
+        .collect())
+}
+
+/// Picks the nodes read from the XML files by their BrowseName or VariableMapping name.
+#[derive(Default)]
+pub struct NodeFilter {
+    /// Only nodes with a name matching this are kept.
+    pub include: Option<Regex>,
+    /// Nodes with a name matching this are dropped, even when they match `include`.
+    pub exclude: Option<Regex>,
+}
+
+impl NodeFilter {
+    fn keeps(&self, names: &[&str]) -> bool {
+        let matches = |pattern: &Regex| names.iter().any(|name| pattern.is_match(name));
+        self.include.as_ref().is_none_or(matches) && !self.exclude.as_ref().is_some_and(matches)
+    }
+}
+
+/// Reads the groups of an exported XML file, recording every prompt answer into `answers`.
+/// Answers ending with `*` are kept in `shared` and used for the `remaining` files.
+/// With `all_namespaces` every namespace the variables use becomes a group of its own, otherwise
+/// the one group holds the namespace of the root object. With `with_browse_paths` each node gets
+/// a `browse_path` tag, which also names nodes the VariableMapping doesn't. Nodes `filter` drops
+/// are counted but left out of the groups.
+#[allow(clippy::too_many_arguments)]
 pub fn parse_xml(
     xml_file: &str,
-    ip: &str,
-    username: &str,
-    password: &str,
     is_listener: bool,
-) -> String {
-    let xml = std::fs::read_to_string(xml_file).expect("Unable to read file");
-    let doc = Document::parse(&xml).expect("Unable to parse XML");
+    all_namespaces: bool,
+    with_browse_paths: bool,
+    filter: &NodeFilter,
+    remaining: &[String],
+    shared: &mut SharedAnswers,
+    answers: &mut Vec<(String, String)>,
+) -> Result<Vec<OpcGroup>, Box<dyn std::error::Error>> {
+    // Read and parse before prompting so a broken file is reported without asking anything
+    let xml = std::fs::read_to_string(xml_file)
+        .map_err(|e| format!("unable to read {}: {}", xml_file, e))?;
+    let doc = Document::parse(&xml).map_err(|e| format!("unable to parse {}: {}", xml_file, e))?;
+    log::debug!("Parsed {} ({} bytes)", xml_file, xml.len());
 
-    // asking for individual namespace numbers
-    println!("----Enter the namespace number for {}:", xml_file);
-    let mut namespace_number = String::new();
-    std::io::stdin().read_line(&mut namespace_number).unwrap();
-    let namespace_number = namespace_number.trim();
+    // With all namespaces the indexes come from the variables, there is nothing to ask
+    let found_namespaces: Vec<String> = variable_namespaces(&doc)
+        .iter()
+        .map(|namespace| namespace.to_string())
+        .collect();
+    if all_namespaces && found_namespaces.is_empty() {
+        return Err(format!("no variables outside namespace 0 found in {}", xml_file).into());
+    }
+
+    // asking for individual namespace numbers, an empty answer takes the one found in the XML
+    let detected = detect_namespace(&doc);
+    let detected_number = detected
+        .as_ref()
+        .map(|(index, _)| index.clone())
+        .unwrap_or_default();
+    let namespace_number = if all_namespaces {
+        let namespaces = found_namespaces.join(", ");
+        say!("----Namespaces found in {}: {}", xml_file, namespaces);
+        namespaces
+    } else {
+        match shared.get(xml_file, "namespace number") {
+            Some(namespace_number) => {
+                let namespace_number = if namespace_number.is_empty() {
+                    detected_number
+                } else {
+                    namespace_number
+                };
+                if namespace_number.is_empty() {
+                    return Err(format!(
+                        "no namespace was found in {}, give its number or URI",
+                        xml_file
+                    )
+                    .into());
+                }
+                match &detected {
+                    Some((index, uri)) if *index == namespace_number => say!(
+                        "----Namespace number for {}: {}, guessed for {} from the XML's NamespaceUris",
+                        xml_file,
+                        namespace_number,
+                        uri
+                    ),
+                    _ => say!(
+                        "----Namespace number for {}: {}",
+                        xml_file,
+                        namespace_number
+                    ),
+                }
+                namespace_number
+            }
+            None => {
+                crate::output::ask(
+                    &format!("the namespace number of {}", xml_file),
+                    "pass --namespace or set `namespace` for the file in a --config file",
+                )?;
+                match &detected {
+                // The index is the URI's place in the export, the server may number it differently
+                Some((index, uri)) => say!(
+                    "----Enter the namespace number or URI for {} (guessed {} for {} from the XML's NamespaceUris, \
+                     the server may number it differently, enter the URI to be sure; press enter to use {}{}):",
+                    xml_file,
+                    index,
+                    uri,
+                    index,
+                    reuse_hint(remaining)
+                ),
+                None => say!(
+                    "----Enter the namespace number or URI for {}{}:",
+                    xml_file,
+                    reuse_hint(remaining)
+                ),
+            }
+                // Without a detected namespace an empty answer would leave the group without one,
+                // piped input gets one try
+                let interactive = std::io::stdin().is_terminal();
+                loop {
+                    let mut namespace_number = String::new();
+                    let read = std::io::stdin().read_line(&mut namespace_number)?;
+                    let (namespace_number, reuse) = strip_reuse(namespace_number.trim());
+                    if namespace_number.is_empty() && detected_number.is_empty() {
+                        if !interactive || read == 0 {
+                            return Err(format!(
+                                "no namespace was found in {}, give its number or URI",
+                                xml_file
+                            )
+                            .into());
+                        }
+                        say!(
+                            "----No namespace was detected in {}, enter its number or URI:",
+                            xml_file
+                        );
+                        continue;
+                    }
+                    if reuse {
+                        shared.share("namespace number", namespace_number, remaining);
+                    }
+                    break if namespace_number.is_empty() {
+                        detected_number
+                    } else {
+                        namespace_number.to_string()
+                    };
+                }
+            }
+        }
+    };
+    answers.push(("namespace".to_string(), namespace_number.clone()));
 
     // ask for intervals
-    let mut interval = String::new();
-    let interval_input = if !is_listener {
-        println!("{}", "----Enter the interval in ms (default 1000ms):");
-        std::io::stdin().read_line(&mut interval).unwrap();
-        interval.trim()
+    let setting = if is_listener {
+        "sampling_interval"
     } else {
-        println!(
-            "{}",
-            "----Enter the sampling_interval in ms (default 1000ms):"
-        );
-        std::io::stdin().read_line(&mut interval).unwrap();
-        interval.trim()
+        "interval"
+    };
+    let interval = match shared.get(xml_file, setting) {
+        Some(interval) => {
+            say!("----{}: {}", setting, interval);
+            interval
+        }
+        None => {
+            crate::output::ask(
+                &format!("the {} of {}", setting, xml_file),
+                "set `interval` for the file in a --config file",
+            )?;
+            let (interval, reuse) = prompt_interval(setting, remaining)?;
+            if reuse {
+                shared.share(setting, &interval, remaining);
+            }
+            interval
+        }
     };
+    answers.push((setting.to_string(), interval.clone()));
 
-    let interval = if interval_input.is_empty() {
-        if !is_listener {
-            "1000ms"
-        } else {
-            "1000ms"
+    // ask for the object whose DisplayName names the group, a bare "i=.." stays in the XML namespace
+    let root_node_id = match shared.get(xml_file, "root NodeId") {
+        Some(root_node_id) => {
+            say!("----Root object NodeId: {}", root_node_id);
+            root_node_id
+        }
+        None => {
+            crate::output::ask(
+                &format!("the root object of {}", xml_file),
+                "set `root` for the file in a --config file",
+            )?;
+            say!(
+                "----Enter the NodeId of the root object naming the group (default ns=2;i=1{}):",
+                reuse_hint(remaining)
+            );
+            let mut root_input = String::new();
+            std::io::stdin().read_line(&mut root_input)?;
+            let (root, reuse) = strip_reuse(root_input.trim());
+            let root_node_id = match root {
+                "" => "ns=2;i=1".to_string(),
+                root if root.starts_with("ns=") => root.to_string(),
+                root => format!("ns=2;{}", root),
+            };
+            if reuse {
+                shared.share("root NodeId", &root_node_id, remaining);
+            }
+            root_node_id
         }
+    };
+    answers.push(("root NodeId".to_string(), root_node_id.clone()));
+    // Variables are read from the XML namespace the root object lives in, or from all of them
+    let xml_namespace = parse_node_id(&root_node_id)
+        .ok_or_else(|| format!("invalid root NodeId '{}'", root_node_id))?
+        .namespace;
+    let xml_namespaces = if all_namespaces {
+        found_namespaces
     } else {
-        interval_input
+        vec![xml_namespace]
     };
+    log::debug!(
+        "Reading the variables of {} in ns={} under {}",
+        xml_file,
+        xml_namespaces.join(","),
+        root_node_id
+    );
 
-    let mut nodes = Vec::new();
+    let mut nodes: Vec<Vec<Node>> = xml_namespaces.iter().map(|_| Vec::new()).collect();
 
     let mut display_name = String::new();
     for variable in doc.descendants().filter(|n| n.has_tag_name("UAObject")) {
         let node_id = variable.attribute("NodeId");
         // Check for the specific node and print its DisplayName
         if let Some(node_id) = node_id {
-            if node_id == "ns=2;i=1" {
+            if node_id == root_node_id {
                 if let Some(found_name) = variable
                     .descendants()
                     .find(|n| n.has_tag_name("DisplayName"))
                     .and_then(|n| n.text())
                 {
                     display_name = found_name.to_string();
-                    println!("##BrowseName for ns=2;i=1: {}", found_name);
+                    say!("##BrowseName for {}: {}", root_node_id, found_name);
                 }
             }
         }
     }
+    let paths = if with_browse_paths {
+        browse_paths(&doc)
+    } else {
+        HashMap::new()
+    };
+    let mut arrays = Vec::new();
+    let (mut filtered, mut read) = (0, 0);
     for variable in doc.descendants().filter(|n| n.has_tag_name("UAVariable")) {
         let node_id = variable.attribute("NodeId");
         if let Some(node_id) = node_id {
-            if node_id.starts_with("ns=2;i=") {
-                let identifier = node_id.split('=').nth(2).unwrap().to_string();
+            let parsed = match parse_node_id(node_id) {
+                Some(parsed) => parsed,
+                None => {
+                    if xml_namespaces
+                        .iter()
+                        .any(|namespace| node_id.starts_with(&format!("ns={};", namespace)))
+                    {
+                        ereport!(
+                            "Warning: skipping malformed NodeId '{}' in {}",
+                            node_id,
+                            xml_file
+                        );
+                    }
+                    continue;
+                }
+            };
+            if let Some(slot) = xml_namespaces
+                .iter()
+                .position(|namespace| *namespace == parsed.namespace)
+            {
+                let NodeId {
+                    identifier,
+                    identifier_type,
+                    ..
+                } = parsed;
 
                 let mut name = variable
                     .descendants()
@@ -196,29 +1621,124 @@ pub fn parse_xml(
                     .and_then(|n| n.text())
                     .unwrap_or_default()
                     .to_string();
+                let mut tags = Vec::new();
+                let mut mapped = false;
 
                 if let Some(var_mapping) = variable
                     .descendants()
                     .find(|n| n.has_tag_name("VariableMapping"))
                     .and_then(|n| n.text())
                 {
-                    let var_mapping = var_mapping.replace('"', "");
-                    name = var_mapping;
+                    let (mapped_name, mapped_tags) = parse_variable_mapping(var_mapping);
+                    if let Some(mapped_name) = mapped_name {
+                        name = mapped_name;
+                        mapped = true;
+                    }
+                    tags = mapped_tags;
                 }
+                if let Some(field) = take_field_name(&mut tags) {
+                    name = field;
+                    mapped = true;
+                }
+                // NodeSet exports carry the BrowseName as an attribute like "2:Temp", the
+                // namespace index in front is left out so ^ anchors at the name
+                read += 1;
+                let browse_name = variable.attribute("BrowseName").unwrap_or_default();
+                let browse_name = match browse_name.split_once(':') {
+                    Some((index, rest)) if index.chars().all(|c| c.is_ascii_digit()) => rest,
+                    _ => browse_name,
+                };
+                if !filter.keeps(&[browse_name, &name]) {
+                    log::trace!("Node {} filtered out", node_id);
+                    filtered += 1;
+                    continue;
+                }
+                if let Some(path) = paths.get(node_id) {
+                    if !mapped {
+                        name = path.clone();
+                    }
+                    if !tags.iter().any(|(key, _)| key == "browse_path") {
+                        tags.push(("browse_path".to_string(), path.clone()));
+                    }
+                }
+                let deadband = take_deadband(&mut tags, node_id);
+                let sampling_interval = take_sampling_interval(&mut tags, node_id);
+                let description = variable
+                    .children()
+                    .find(|n| n.has_tag_name("Description"))
+                    .and_then(|n| n.text())
+                    .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .filter(|text| !text.is_empty());
 
-                nodes.push(format!(
-                    "{{name=\"{}\", identifier=\"{}\"}}",
-                    name, identifier
-                ));
+                // Tag each node with its type so mismatched fields can be traced in InfluxDB
+                if let Some(data_type) = variable.attribute("DataType") {
+                    match data_type_name(data_type) {
+                        Some(type_name) => {
+                            if !tags.iter().any(|(key, _)| key == "datatype") {
+                                tags.push(("datatype".to_string(), type_name.to_string()));
+                            }
+                        }
+                        None => ereport!(
+                            "Warning: unknown DataType '{}' for NodeId '{}', no datatype tag added",
+                            data_type,
+                            node_id
+                        ),
+                    }
+                }
+
+                if let Some(dimensions) = array_dimensions(variable) {
+                    if !tags.iter().any(|(key, _)| key == "array") {
+                        tags.push(("array".to_string(), dimensions.clone()));
+                    }
+                    arrays.push(format!("{} '{}' ({})", node_id, name, dimensions));
+                }
+
+                log::trace!("Node {} named '{}' with tags {:?}", node_id, name, tags);
+                nodes[slot].push(Node {
+                    name,
+                    identifier,
+                    identifier_type,
+                    tags,
+                    deadband,
+                    sampling_interval,
+                    description,
+                });
             }
         }
     }
 
-    let nodes_str = nodes.join(",\n        ");
+    log::debug!(
+        "Read {} variables from {}",
+        nodes.iter().map(Vec::len).sum::<usize>(),
+        xml_file
+    );
+    if filtered > 0 {
+        say!(
+            "----Filtered out {} of {} nodes in {}",
+            filtered,
+            read,
+            xml_file
+        );
+    }
+    if !arrays.is_empty() {
+        // Telegraf can't address single elements, so these need handling downstream
+        ereport!(
+            "Warning: {} array node(s) in {} are read as a whole and tagged with their dimensions as 'array':",
+            arrays.len(),
+            xml_file
+        );
+        for array in &arrays {
+            ereport!("  {}", array);
+        }
+    }
 
     let group_name = if !display_name.is_empty() {
         display_name.to_string()
     } else {
+        say!(
+            "No DisplayName found for root object {}, using the file name as group name.",
+            root_node_id
+        );
         std::path::Path::new(xml_file)
             .file_stem()
             .and_then(|s| s.to_str())
@@ -226,25 +1746,154 @@ pub fn parse_xml(
             .to_string()
     };
 
-    if is_listener {
-        format_listener_config(
-            ip,
-            username,
-            password,
-            &group_name,
-            namespace_number,
-            interval,
-            &nodes_str,
-        )
-    } else {
-        format_standard_config(
-            ip,
-            username,
-            password,
-            &group_name,
-            namespace_number,
-            interval,
-            &nodes_str,
-        )
+    // Groups of one input need names of their own, the namespace tells them apart
+    let split = xml_namespaces.len() > 1;
+    Ok(xml_namespaces
+        .into_iter()
+        .zip(nodes)
+        .map(|(xml_namespace, nodes)| OpcGroup {
+            name: if split {
+                format!("{}_ns{}", group_name, xml_namespace)
+            } else {
+                group_name.clone()
+            },
+            namespace: if all_namespaces {
+                xml_namespace
+            } else {
+                namespace_number.clone()
+            },
+            interval: interval.clone(),
+            is_listener,
+            nodes,
+            measurement: None,
+            display_name: split.then(|| group_name.clone()),
+            source: xml_file.to_string(),
+            timestamp: "source".to_string(),
+            bucket: shared.get(xml_file, "bucket"),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id(node_id: &str) -> Option<(String, String, String)> {
+        parse_node_id(node_id).map(|id| (id.namespace, id.identifier_type, id.identifier))
+    }
+
+    fn parts(
+        namespace: &str,
+        identifier_type: &str,
+        identifier: &str,
+    ) -> Option<(String, String, String)> {
+        Some((
+            namespace.to_string(),
+            identifier_type.to_string(),
+            identifier.to_string(),
+        ))
+    }
+
+    #[test]
+    fn node_ids_are_split_into_their_parts() {
+        assert_eq!(node_id("ns=2;i=5"), parts("2", "i", "5"));
+        assert_eq!(node_id("i=2253"), parts("0", "i", "2253"));
+        assert_eq!(
+            node_id("ns=3;s=\"DB1\".\"Speed\""),
+            parts("3", "s", "\"DB1\".\"Speed\"")
+        );
+        assert_eq!(
+            node_id("ns=4;g=09087e75-8e5e-499b-954f-f2a9603db28a"),
+            parts("4", "g", "09087e75-8e5e-499b-954f-f2a9603db28a")
+        );
+        assert_eq!(
+            node_id("ns=2;b=M/RbKBsRVkePCePcx24oRA=="),
+            parts("2", "b", "M/RbKBsRVkePCePcx24oRA==")
+        );
+    }
+
+    #[test]
+    fn string_identifiers_keep_separators() {
+        assert_eq!(node_id("ns=2;s=a=b;c d"), parts("2", "s", "a=b;c d"));
+    }
+
+    #[test]
+    fn malformed_node_ids_are_rejected() {
+        for malformed in [
+            "",
+            "ns=2",
+            "ns=2;",
+            "ns=x;i=5",
+            "ns=;i=5",
+            "ns=2;i=",
+            "ns=2;i=abc",
+            "ns=2;q=5",
+            "5",
+        ] {
+            assert!(
+                parse_node_id(malformed).is_none(),
+                "{} was accepted",
+                malformed
+            );
+        }
+    }
+
+    fn group(namespace: &str) -> OpcGroup {
+        OpcGroup {
+            name: "Press".to_string(),
+            namespace: namespace.to_string(),
+            interval: "1000ms".to_string(),
+            is_listener: false,
+            nodes: Vec::new(),
+            measurement: None,
+            display_name: None,
+            source: "press.xml".to_string(),
+            timestamp: "source".to_string(),
+            bucket: None,
+        }
+    }
+
+    #[test]
+    fn namespaces_are_written_by_index_or_uri() {
+        assert_eq!(group("3").namespace_line(), "\n      namespace = \"3\"");
+        assert_eq!(
+            group("urn:press").namespace_line(),
+            "\n      namespace_uri = \"urn:press\""
+        );
+        assert_eq!(group("").namespace_line(), "");
+    }
+
+    #[test]
+    fn node_filters_match_any_name() {
+        let filter = NodeFilter {
+            include: Some(Regex::new("(?i)^temp").unwrap()),
+            exclude: Some(Regex::new("_Spare$").unwrap()),
+        };
+        assert!(filter.keeps(&["Temp_1"]));
+        assert!(filter.keeps(&["Speed", "temp_motor"]));
+        assert!(!filter.keeps(&["Speed"]));
+        assert!(!filter.keeps(&["Temp_Spare"]));
+        assert!(NodeFilter::default().keeps(&["anything"]));
+    }
+
+    #[test]
+    fn escape_toml_escapes_what_basic_strings_can_not_hold() {
+        assert_eq!(escape_toml("plain"), "plain");
+        assert_eq!(
+            escape_toml("\"DB1\".\"Speed\""),
+            "\\\"DB1\\\".\\\"Speed\\\""
+        );
+        assert_eq!(escape_toml("C:\\certs"), "C:\\\\certs");
+        assert_eq!(escape_toml("a\nb\r\tc"), "a\\nb\\r\\tc");
+        assert_eq!(escape_toml("bell\u{7}"), "bell\\u0007");
+        assert_eq!(escape_toml("Temperatur °C"), "Temperatur °C");
+    }
+
+    #[test]
+    fn escaped_values_read_back_the_same() {
+        let value = "quote \" backslash \\ newline \n tab \t del \u{7f}";
+        let table: toml::Table =
+            toml::from_str(&format!("value = \"{}\"", escape_toml(value))).unwrap();
+        assert_eq!(table["value"].as_str(), Some(value));
     }
 }
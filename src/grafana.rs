@@ -0,0 +1,106 @@
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+fn get_json(url: &str, token: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    // Grafana answers errors with a status code, which ureq already turns into an Err
+    log::debug!("GET {}", url);
+    let value = ureq::get(url)
+        .header("Authorization", &format!("Bearer {}", token))
+        .call()?
+        .body_mut()
+        .read_json::<Value>()?;
+    Ok(value)
+}
+
+fn dashboard_file_name(uid: &str) -> String {
+    // UIDs like "a-b" and "a_b" sanitize the same, so those that change get a hash of the raw UID
+    let sanitized = crate::format::sanitize_name(uid);
+    if sanitized == uid {
+        return format!("{}.json", uid);
+    }
+    let hash: String = Sha256::digest(uid.as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if sanitized.is_empty() {
+        format!("{}.json", hash)
+    } else {
+        format!("{}-{}.json", sanitized, hash)
+    }
+}
+
+pub fn backup_grafana_dashboards(
+    grafana_url: &str,
+    token: &str,
+    local_directory: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Dashboards live in Grafana's database, so they are fetched over the HTTP API instead of SFTP
+    let grafana_url = grafana_url.trim_end_matches('/');
+    say!("Listing dashboards on {} ..", grafana_url);
+    let search = get_json(
+        &format!("{}/api/search?type=dash-db&limit=5000", grafana_url),
+        token,
+    )?;
+    let dashboards = search
+        .as_array()
+        .ok_or("unexpected answer from /api/search, expected a list of dashboards")?;
+
+    std::fs::create_dir_all(local_directory)?;
+    for dashboard in dashboards {
+        let uid = dashboard
+            .get("uid")
+            .and_then(Value::as_str)
+            .ok_or("/api/search returned a dashboard without a uid")?;
+        let title = dashboard
+            .get("title")
+            .and_then(Value::as_str)
+            .unwrap_or(uid);
+
+        // Keep the whole answer, the meta block records the folder the dashboard belongs in
+        let content = get_json(
+            &format!("{}/api/dashboards/uid/{}", grafana_url, uid),
+            token,
+        )?;
+        let local_path = local_directory.join(dashboard_file_name(uid));
+        std::fs::write(&local_path, serde_json::to_string_pretty(&content)?)?;
+        say!("Saved dashboard '{}' to {}", title, local_path.display());
+    }
+
+    println!(
+        "Backed up {} dashboards to {}",
+        dashboards.len(),
+        local_directory.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_uids_keep_their_name() {
+        assert_eq!(dashboard_file_name("machines01"), "machines01.json");
+        assert_eq!(dashboard_file_name("press_line"), "press_line.json");
+    }
+
+    #[test]
+    fn sanitized_uids_do_not_collide() {
+        let dashed = dashboard_file_name("press-line");
+        let spaced = dashboard_file_name("press line");
+        assert!(dashed.starts_with("press_line-"), "{}", dashed);
+        assert!(spaced.starts_with("press_line-"), "{}", spaced);
+        assert_ne!(dashed, spaced);
+        assert_ne!(dashed, dashboard_file_name("press_line"));
+        // The same UID gets the same name in every backup
+        assert_eq!(dashed, dashboard_file_name("press-line"));
+    }
+
+    #[test]
+    fn uids_without_letters_or_digits_get_a_name() {
+        let name = dashboard_file_name("--");
+        assert_eq!(name.len(), "01234567.json".len());
+        assert_ne!(name, dashboard_file_name("-_-"));
+    }
+}
@@ -0,0 +1,407 @@
+use crate::format::{
+    AgentSettings, AuthMethod, Deadband, InfluxOutput, Node, OpcGroup, OpcSettings, BUCKET_TAG,
+};
+use toml::{Table, Value};
+
+/// Everything recovered from a previously generated telegraf.conf.
+pub struct ImportedConfig {
+    pub global_tags: Vec<(String, String)>,
+    pub agent: AgentSettings,
+    pub outputs: Vec<InfluxOutput>,
+    pub opc: OpcSettings,
+    pub groups: Vec<OpcGroup>,
+}
+
+fn get_str(table: &Table, key: &str) -> Option<String> {
+    table.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+fn get_u32(table: &Table, key: &str) -> Option<u32> {
+    table
+        .get(key)
+        .and_then(Value::as_integer)
+        .and_then(|value| u32::try_from(value).ok())
+}
+
+fn tables<'a>(table: &'a Table, key: &str) -> Vec<&'a Table> {
+    // Arrays of tables like [[inputs.opcua]] come back as arrays, a missing key as nothing
+    table
+        .get(key)
+        .and_then(Value::as_array)
+        .map(|array| array.iter().filter_map(Value::as_table).collect())
+        .unwrap_or_default()
+}
+
+fn parse_endpoint(endpoint: &str) -> Option<String> {
+    // Reverses the "opc.tcp://<ip>:4840" endpoint the formatters emit
+    let address = endpoint.strip_prefix("opc.tcp://")?;
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => address,
+    };
+    Some(host.trim_matches(['[', ']']).to_string())
+}
+
+fn parse_input(
+    path: &str,
+    input: &Table,
+    is_listener: bool,
+    groups: &mut Vec<OpcGroup>,
+) -> Result<OpcSettings, Box<dyn std::error::Error>> {
+    let default_name = if is_listener {
+        "opcua_listener"
+    } else {
+        "opcua"
+    };
+    let certificate = get_str(input, "certificate").unwrap_or_default();
+    let private_key = get_str(input, "private_key").unwrap_or_default();
+    let auth = match get_str(input, "auth_method").as_deref() {
+        Some("Anonymous") => AuthMethod::Anonymous,
+        Some("Certificate") => AuthMethod::Certificate {
+            certificate: certificate.clone(),
+            private_key: private_key.clone(),
+        },
+        _ => AuthMethod::UserName,
+    };
+    // Other auth methods can still sign and encrypt with a certificate of their own
+    let client_certificate = (!matches!(auth, AuthMethod::Certificate { .. })
+        && !certificate.is_empty())
+    .then_some((certificate, private_key));
+    let endpoint = get_str(input, "endpoint").ok_or("input without an endpoint")?;
+    let opc = OpcSettings {
+        ip: parse_endpoint(&endpoint)
+            .ok_or_else(|| format!("unsupported endpoint '{}'", endpoint))?,
+        username: get_str(input, "username").unwrap_or_default(),
+        password: get_str(input, "password").unwrap_or_default(),
+        application_name: get_str(input, "application_name"),
+        product_uri: get_str(input, "product_uri"),
+        auth,
+        client_certificate,
+        security_policy: get_str(input, "security_policy")
+            .unwrap_or_else(|| "Basic256Sha256".to_string()),
+        security_mode: get_str(input, "security_mode")
+            .unwrap_or_else(|| "SignAndEncrypt".to_string()),
+        connect_timeout: get_str(input, "connect_timeout").unwrap_or_else(|| "30s".to_string()),
+        request_timeout: get_str(input, "request_timeout").unwrap_or_else(|| "10s".to_string()),
+        session_timeout: get_str(input, "session_timeout").unwrap_or_else(|| "20m".to_string()),
+        subscription_interval: get_str(input, "subscription_interval"),
+    };
+    let measurement = get_str(input, "name").filter(|name| name != default_name);
+    let timestamp = get_str(input, "timestamp").unwrap_or_else(|| "source".to_string());
+
+    for group in tables(input, "group") {
+        let identifier_type = get_str(group, "identifier_type").unwrap_or_else(|| "i".to_string());
+        // Standard inputs poll on the plugin interval, listeners sample per group
+        let interval = if is_listener {
+            get_str(group, "sampling_interval")
+        } else {
+            get_str(input, "interval")
+        };
+        let default_tags = group.get("default_tags").and_then(Value::as_table);
+        let nodes = group
+            .get("nodes")
+            .and_then(Value::as_array)
+            .map(|nodes| {
+                nodes
+                    .iter()
+                    .filter_map(Value::as_table)
+                    .map(|node| Node {
+                        name: get_str(node, "name").unwrap_or_default(),
+                        identifier: get_str(node, "identifier").unwrap_or_default(),
+                        identifier_type: get_str(node, "identifier_type")
+                            .unwrap_or_else(|| identifier_type.clone()),
+                        tags: node
+                            .get("tags")
+                            .and_then(Value::as_array)
+                            .map(|tags| {
+                                tags.iter()
+                                    .filter_map(Value::as_array)
+                                    .filter_map(|pair| match pair.as_slice() {
+                                        [key, value] => Some((
+                                            key.as_str()?.to_string(),
+                                            value.as_str()?.to_string(),
+                                        )),
+                                        _ => None,
+                                    })
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        sampling_interval: node
+                            .get("monitoring_params")
+                            .and_then(Value::as_table)
+                            .and_then(|params| get_str(params, "sampling_interval")),
+                        deadband: node
+                            .get("monitoring_params")
+                            .and_then(Value::as_table)
+                            .and_then(|params| params.get("data_change_filter"))
+                            .and_then(Value::as_table)
+                            .and_then(|filter| {
+                                Some(Deadband {
+                                    kind: get_str(filter, "deadband_type")?,
+                                    value: filter.get("deadband_value")?.as_float()?,
+                                })
+                            }),
+                        // Comments don't survive parsing the TOML
+                        description: None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        groups.push(OpcGroup {
+            name: get_str(group, "name").unwrap_or_default(),
+            namespace: get_str(group, "namespace")
+                .or_else(|| get_str(group, "namespace_uri"))
+                .unwrap_or_default(),
+            interval: interval.unwrap_or_else(|| "1000ms".to_string()),
+            is_listener,
+            nodes,
+            measurement: measurement.clone(),
+            display_name: default_tags.and_then(|tags| get_str(tags, "display_name")),
+            source: path.to_string(),
+            timestamp: timestamp.clone(),
+            bucket: default_tags.and_then(|tags| get_str(tags, BUCKET_TAG)),
+        });
+    }
+
+    Ok(opc)
+}
+
+pub fn import_config(path: &str) -> Result<ImportedConfig, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let config: Table = toml::from_str(&content)?;
+
+    let empty = Table::new();
+    let agent = config
+        .get("agent")
+        .and_then(Value::as_table)
+        .unwrap_or(&empty);
+    let agent = AgentSettings {
+        interval: get_str(agent, "interval").unwrap_or_else(|| "1000ms".to_string()),
+        flush_interval: get_str(agent, "flush_interval").unwrap_or_else(|| "10s".to_string()),
+        metric_batch_size: get_u32(agent, "metric_batch_size").unwrap_or(10000),
+        metric_buffer_limit: get_u32(agent, "metric_buffer_limit").unwrap_or(100000),
+    };
+
+    let global_tags = config
+        .get("global_tags")
+        .and_then(Value::as_table)
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let outputs = config
+        .get("outputs")
+        .and_then(Value::as_table)
+        .map(|outputs| tables(outputs, "influxdb_v2"))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|output| InfluxOutput {
+            url: output
+                .get("urls")
+                .and_then(Value::as_array)
+                .and_then(|urls| urls.first())
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            token: get_str(output, "token").unwrap_or_default(),
+            organization: get_str(output, "organization").unwrap_or_default(),
+            bucket: get_str(output, "bucket").unwrap_or_default(),
+            bucket_tag: get_str(output, "bucket_tag").is_some(),
+        })
+        .collect();
+
+    let inputs = config
+        .get("inputs")
+        .and_then(Value::as_table)
+        .unwrap_or(&empty);
+
+    let mut groups = Vec::new();
+    let mut settings = Vec::new();
+    for input in tables(inputs, "opcua") {
+        settings.push(parse_input(path, input, false, &mut groups)?);
+    }
+    for input in tables(inputs, "opcua_listener") {
+        settings.push(parse_input(path, input, true, &mut groups)?);
+    }
+
+    // Only listener inputs carry a subscription interval, which the first input may not be
+    let subscription_interval = settings
+        .iter()
+        .find_map(|opc| opc.subscription_interval.clone());
+    // The generator writes the same connection settings into every input, so the first one wins
+    let mut settings = settings.into_iter();
+    let mut opc = settings
+        .next()
+        .ok_or("no [[inputs.opcua]] or [[inputs.opcua_listener]] blocks found")?;
+    opc.subscription_interval = subscription_interval;
+    if settings.any(|other| other.ip != opc.ip || other.username != opc.username) {
+        println!(
+            "Warning: inputs in {} use different endpoints or credentials, using those of the first one.",
+            path
+        );
+    }
+
+    Ok(ImportedConfig {
+        global_tags,
+        agent,
+        outputs,
+        opc,
+        groups,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[global_tags]
+site = "Plant 1"
+
+[agent]
+interval = "5s"
+flush_interval = "15s"
+metric_batch_size = 500
+
+[[outputs.influxdb_v2]]
+urls = ["http://127.0.0.1:8086"]
+token = "secret"
+organization = "siemens"
+bucket = "machines"
+
+[[inputs.opcua]]
+name = "press"
+interval = "2000ms"
+endpoint = "opc.tcp://192.168.0.20:4840"
+security_policy = "None"
+security_mode = "None"
+certificate = "/etc/telegraf/telegraf_client.crt"
+private_key = "/etc/telegraf/telegraf_client.key"
+auth_method = "UserName"
+username = "admin"
+password = "pw"
+timestamp = "gather"
+    [[inputs.opcua.group]]
+      name = "Press_Line_1"
+      default_tags = {display_name = "Press Line/1", bucket = "press"}
+      namespace = "2"
+      identifier_type = "i"
+      nodes = [
+        # Oil temperature
+        {name="Temp", identifier="5", tags=[["datatype", "Double"]]},
+        {name="Speed", identifier="DB1.Speed", identifier_type="s"}
+      ]
+
+[[inputs.opcua_listener]]
+name = "opcua_listener"
+endpoint = "opc.tcp://192.168.0.20:4840"
+subscription_interval = "100ms"
+auth_method = "Anonymous"
+    [[inputs.opcua_listener.group]]
+      name = "Robot"
+      namespace_uri = "urn:robot"
+      identifier_type = "i"
+      sampling_interval = "250ms"
+      nodes = [
+        {name="Torque", identifier="7", monitoring_params={sampling_interval="50ms", data_change_filter={trigger="StatusValue", deadband_type="Absolute", deadband_value=0.5}}}
+      ]
+"#;
+
+    fn import(name: &str, content: &str) -> Result<ImportedConfig, Box<dyn std::error::Error>> {
+        let path =
+            std::env::temp_dir().join(format!("import_{}_{}.conf", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let imported = import_config(&path.display().to_string());
+        std::fs::remove_file(&path).unwrap();
+        imported
+    }
+
+    #[test]
+    fn endpoints_give_back_the_host() {
+        assert_eq!(
+            parse_endpoint("opc.tcp://192.168.0.20:4840").as_deref(),
+            Some("192.168.0.20")
+        );
+        assert_eq!(parse_endpoint("opc.tcp://plc").as_deref(), Some("plc"));
+        assert_eq!(
+            parse_endpoint("opc.tcp://[fe80::1]:4840").as_deref(),
+            Some("fe80::1")
+        );
+        assert_eq!(parse_endpoint("http://plc:4840"), None);
+    }
+
+    #[test]
+    fn settings_are_read_back() {
+        let imported = import("settings", CONFIG).unwrap();
+        assert_eq!(
+            imported.global_tags,
+            [("site".to_string(), "Plant 1".to_string())]
+        );
+        assert_eq!(imported.agent.interval, "5s");
+        assert_eq!(imported.agent.flush_interval, "15s");
+        assert_eq!(imported.agent.metric_batch_size, 500);
+        assert_eq!(imported.agent.metric_buffer_limit, 100000);
+        assert_eq!(imported.outputs.len(), 1);
+        assert_eq!(imported.outputs[0].url, "http://127.0.0.1:8086");
+        assert_eq!(imported.outputs[0].bucket, "machines");
+        assert!(!imported.outputs[0].bucket_tag);
+        assert_eq!(imported.opc.ip, "192.168.0.20");
+        assert_eq!(imported.opc.username, "admin");
+        assert_eq!(imported.opc.security_policy, "None");
+        assert!(matches!(imported.opc.auth, AuthMethod::UserName));
+        assert!(imported.opc.client_certificate.is_some());
+        // Taken from the listener, the standard input has none
+        assert_eq!(imported.opc.subscription_interval.as_deref(), Some("100ms"));
+    }
+
+    #[test]
+    fn standard_groups_are_read_back() {
+        let imported = import("standard", CONFIG).unwrap();
+        let group = &imported.groups[0];
+        assert_eq!(group.name, "Press_Line_1");
+        assert!(!group.is_listener);
+        assert_eq!(group.namespace, "2");
+        assert_eq!(group.interval, "2000ms");
+        assert_eq!(group.measurement.as_deref(), Some("press"));
+        assert_eq!(group.display_name.as_deref(), Some("Press Line/1"));
+        assert_eq!(group.bucket.as_deref(), Some("press"));
+        assert_eq!(group.timestamp, "gather");
+        assert_eq!(group.nodes.len(), 2);
+        assert_eq!(group.nodes[0].name, "Temp");
+        assert_eq!(group.nodes[0].identifier_type, "i");
+        assert_eq!(
+            group.nodes[0].tags,
+            [("datatype".to_string(), "Double".to_string())]
+        );
+        assert_eq!(group.nodes[1].identifier, "DB1.Speed");
+        assert_eq!(group.nodes[1].identifier_type, "s");
+    }
+
+    #[test]
+    fn listener_groups_are_read_back() {
+        let imported = import("listener", CONFIG).unwrap();
+        let group = &imported.groups[1];
+        assert_eq!(group.name, "Robot");
+        assert!(group.is_listener);
+        assert_eq!(group.namespace, "urn:robot");
+        assert_eq!(group.interval, "250ms");
+        // The listener's name is the default, so it doesn't override the measurement
+        assert_eq!(group.measurement, None);
+        let node = &group.nodes[0];
+        assert_eq!(node.sampling_interval.as_deref(), Some("50ms"));
+        let deadband = node.deadband.as_ref().unwrap();
+        assert_eq!(deadband.kind, "Absolute");
+        assert_eq!(deadband.value, 0.5);
+    }
+
+    #[test]
+    fn configs_without_opc_inputs_are_rejected() {
+        assert!(import("empty", "[agent]\ninterval = \"1s\"\n").is_err());
+        assert!(import("invalid", "[agent\n").is_err());
+        assert!(import("no_endpoint", "[[inputs.opcua]]\nname = \"opcua\"\n").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+/// How long the token check waits for InfluxDB, it should answer right away.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns what looks wrong with an InfluxDB token, None when it could be one. Tokens made by
+/// InfluxDB are 88 characters of base64, so anything much shorter or with spaces was most
+/// likely pasted from the wrong place.
+pub fn token_problem(token: &str) -> Option<String> {
+    if token.is_empty() {
+        return Some("is empty".to_string());
+    }
+    // The header scheme belongs to the HTTP request, Telegraf adds it itself
+    if token.starts_with("Token ") || token.starts_with("Bearer ") {
+        return Some("starts with 'Token' or 'Bearer', which isn't part of it".to_string());
+    }
+    if token.chars().any(char::is_whitespace) {
+        return Some("contains whitespace".to_string());
+    }
+    if token.chars().any(|c| !c.is_ascii_graphic()) {
+        return Some("contains characters a token never has".to_string());
+    }
+    let length = token.chars().count();
+    if !(20..=256).contains(&length) {
+        return Some(format!(
+            "is {} characters long, InfluxDB tokens are usually 88",
+            length
+        ));
+    }
+    None
+}
+
+/// The URL to check a token against from here. Telegraf writing to 127.0.0.1 or localhost means
+/// the InfluxDB on the IOT-2050, so those are asked on `iot_host` instead, given without port.
+pub fn reachable_url(url: &str, iot_host: &str) -> String {
+    for local in ["127.0.0.1", "localhost", "[::1]"] {
+        for scheme in ["http://", "https://"] {
+            let prefix = format!("{}{}", scheme, local);
+            if let Some(rest) = url.strip_prefix(&prefix) {
+                if rest.is_empty() || rest.starts_with([':', '/']) {
+                    return format!("{}{}{}", scheme, iot_host, rest);
+                }
+            }
+        }
+    }
+    url.to_string()
+}
+
+/// Asks the InfluxDB at `url` for its buckets with `token`, which fails when the token is unknown.
+/// Tokens that may only write can't list buckets, but are accepted all the same.
+pub fn verify_token(url: &str, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let endpoint = format!("{}/api/v2/buckets?limit=1", url.trim_end_matches('/'));
+    log::debug!("GET {}", endpoint);
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .into();
+    match agent
+        .get(&endpoint)
+        .header("Authorization", &format!("Token {}", token))
+        .call()
+    {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::StatusCode(403)) => {
+            log::debug!("{} knows the token, but it may not list buckets", url);
+            Ok(())
+        }
+        Err(ureq::Error::StatusCode(401)) => Err("InfluxDB doesn't accept the token".into()),
+        Err(ureq::Error::StatusCode(status)) => {
+            Err(format!("InfluxDB answered with HTTP status {}", status).into())
+        }
+        Err(e) => Err(format!("unable to reach InfluxDB: {}", e).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str =
+        "sH3nM2ZbB9xWJb1xxn6Cj1tJm4PCgDk0WxGGRgVK9PzR3rd0GwKQ0Pv7qsmZBpYtB31NoFZhRLequIlgdWbTYw==";
+
+    #[test]
+    fn influxdb_tokens_pass() {
+        assert_eq!(TOKEN.len(), 88);
+        assert_eq!(token_problem(TOKEN), None);
+        assert_eq!(token_problem(&TOKEN[..20]), None);
+    }
+
+    #[test]
+    fn pasted_mistakes_are_named() {
+        assert_eq!(token_problem("").as_deref(), Some("is empty"));
+        assert_eq!(
+            token_problem(&format!("Token {}", TOKEN)).as_deref(),
+            Some("starts with 'Token' or 'Bearer', which isn't part of it")
+        );
+        assert_eq!(
+            token_problem(&format!("Bearer {}", TOKEN)).as_deref(),
+            Some("starts with 'Token' or 'Bearer', which isn't part of it")
+        );
+        assert_eq!(
+            token_problem(&format!("{}\n", TOKEN)).as_deref(),
+            Some("contains whitespace")
+        );
+        assert_eq!(
+            token_problem(&format!("{}\u{e9}", TOKEN)).as_deref(),
+            Some("contains characters a token never has")
+        );
+        assert_eq!(
+            token_problem("my-token").as_deref(),
+            Some("is 8 characters long, InfluxDB tokens are usually 88")
+        );
+        assert!(token_problem(&"A".repeat(257)).is_some());
+    }
+
+    #[test]
+    fn local_urls_are_asked_on_the_iot2050() {
+        assert_eq!(
+            reachable_url("http://127.0.0.1:8086", "192.168.0.10"),
+            "http://192.168.0.10:8086"
+        );
+        assert_eq!(
+            reachable_url("https://localhost/influx", "iot"),
+            "https://iot/influx"
+        );
+        assert_eq!(reachable_url("http://[::1]", "iot"), "http://iot");
+        assert_eq!(
+            reachable_url("http://localhost.example.com:8086", "iot"),
+            "http://localhost.example.com:8086"
+        );
+        assert_eq!(
+            reachable_url("http://influx.plant:8086", "iot"),
+            "http://influx.plant:8086"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Marks an output folder as being written to by a running generate.
+const LOCK_NAME: &str = ".telegraf-config.lock";
+
+/// A lock this old may be broken with --force, its run most likely died without cleaning up.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The lock held by this run, removed again on exit.
+static HELD: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown host".to_string())
+}
+
+fn create(path: &Path) -> std::io::Result<()> {
+    // create_new fails when the file exists, so only one run can get the lock
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    writeln!(
+        file,
+        "PID {} on {} since {}",
+        std::process::id(),
+        host_name(),
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+    )
+}
+
+/// Takes the lock of `directory` for the rest of the run. A lock another run holds is an error
+/// naming its holder, unless it is older than an hour and `force` is given.
+pub fn acquire(directory: &Path, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(directory)?;
+    let path = directory.join(LOCK_NAME);
+    match create(&path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let holder = std::fs::read_to_string(&path).unwrap_or_default();
+            let holder = holder.trim();
+            let age = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .unwrap_or_default();
+            if !(force && age >= STALE_AFTER) {
+                let hint = if age >= STALE_AFTER {
+                    "add --force to break it"
+                } else {
+                    "wait for that run to finish"
+                };
+                return Err(format!(
+                    "{} is in use by another run ({}), {}",
+                    directory.display(),
+                    if holder.is_empty() {
+                        "holder unknown"
+                    } else {
+                        holder
+                    },
+                    hint
+                )
+                .into());
+            }
+            println!(
+                "Warning: breaking the lock of {} held by {}.",
+                directory.display(),
+                holder
+            );
+            std::fs::remove_file(&path)?;
+            create(&path)?;
+        }
+        created => created?,
+    }
+    log::debug!("Locked {}", directory.display());
+    *HELD.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(path);
+    Ok(())
+}
+
+/// Gives the lock back, if this run holds one.
+pub fn release() {
+    let held = HELD
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    if let Some(path) = held {
+        let _ = std::fs::remove_file(path);
+    }
+}
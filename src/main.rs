@@ -1,43 +1,72 @@
-use clap::{Arg, ArgAction, Command};
+use clap::parser::ValueSource;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::{env, path::Path, path::PathBuf};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
+#[macro_use]
+mod output;
+
+mod cert;
+mod cli;
+mod diff;
 mod format;
+mod grafana;
+mod import;
+mod influx;
+mod lock;
+mod merge;
+mod opc_check;
+mod profile;
 mod ssh_utils;
+mod summary;
 
-fn print_config(matches: &clap::ArgMatches) {
-    println!("Current configuration:");
-    println!("=====================");
-    println!("Folder: {}", matches.get_one::<String>("folder").unwrap());
-    println!("IP: {}", matches.get_one::<String>("ip").unwrap());
-    println!(
-        "Username: {}",
-        matches.get_one::<String>("username").unwrap()
-    );
-    println!(
-        "IOT Host: {}",
-        matches.get_one::<String>("iot_host").unwrap()
-    );
-    println!(
-        "Token Folder: {}",
-        matches.get_one::<String>("token").unwrap()
-    );
-    println!("Send config: {}", matches.get_flag("send"));
-    println!("Backup InfluxDB: {}", matches.get_flag("backup_influx"));
-    println!("Backup Grafana: {}", matches.get_flag("backup_grafana"));
-    println!("=====================\n");
+const REMOTE_CERTIFICATE_PATH: &str = "/etc/telegraf/telegraf_client.crt";
+const REMOTE_PRIVATE_KEY_PATH: &str = "/etc/telegraf/telegraf_client.key";
+const ERROR_LOG_NAME: &str = "generation_errors.log";
+
+fn print_config(command: &str, matches: &clap::ArgMatches) {
+    say!("Current configuration:");
+    say!("=====================");
+    say!("Command: {}", command);
+    if command == "generate" {
+        say!("Folder: {}", matches.get_one::<String>("folder").unwrap());
+        say!("IP: {}", matches.get_one::<String>("ip").unwrap());
+        say!(
+            "Username: {}",
+            matches.get_one::<String>("username").unwrap()
+        );
+        say!(
+            "Token Folder: {}",
+            matches.get_one::<String>("token").unwrap()
+        );
+    }
+    // diff only reads local files
+    if command != "diff" {
+        say!("IOT Host: {}", iot_hosts(matches).join(", "));
+    }
+    say!("=====================\n");
 }
 
-fn get_default_path() -> PathBuf {
-    // Returns the default path by getting the current executable's directory
-    let mut path = env::current_exe().unwrap();
-    path.pop();
-    path
+fn bin_name() -> String {
+    // The binary is often renamed, e.g. to config_generator, so the name it was called by counts
+    std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| env!("CARGO_BIN_NAME").to_string())
 }
 
-fn wrap_up(exit_code: i32) {
-    if cfg!(target_os = "windows") {
+fn wrap_up(exit_code: i32) -> ! {
+    lock::release();
+    // Scripted quiet runs have nobody to press enter
+    if cfg!(target_os = "windows") && !output::is_quiet() && !output::is_no_input() {
         println!("Press enter to exit");
         io::stdout().flush().unwrap();
         let _ = io::stdin().read(&mut [0]).unwrap();
@@ -45,184 +74,668 @@ fn wrap_up(exit_code: i32) {
     std::process::exit(exit_code);
 }
 
-fn main() {
-    // Main function: Parses command-line arguments and either sends a config file or generates one based on XML files
-    let matches = Command::new("IOT2050 config handler")
-        .version("0.4")
-        .about("Generates a config file for Telegraf from XML files in the folder")
-        .arg(
-            Arg::new("folder")
-                .short('f')
-                .long("folder")
-                .value_name("FOLDER")
-                .help("Sets the folder containing the XML files")
-                .default_value(get_default_path().into_os_string()),
-        )
-        .arg(
-            Arg::new("ip")
-                .short('i')
-                .long("ip")
-                .value_name("IP")
-                .help("Sets the OPC IP address")
-                .default_value(env!("DEFAULT_IP")),
-        )
-        .arg(
-            Arg::new("username")
-                .short('u')
-                .long("username")
-                .value_name("USERNAME")
-                .help("Sets the OPC username")
-                .default_value(env!("DEFAULT_USERNAME")),
-        )
-        .arg(
-            Arg::new("password")
-                .short('p')
-                .long("password")
-                .value_name("PASSWORD")
-                .help("Sets the OPC password")
-                .default_value(env!("DEFAULT_PASSWORD")),
-        )
-        .arg(
-            Arg::new("iot_password")
-                .short('w')
-                .long("iot-password")
-                .value_name("IOT_PASSWORD")
-                .help("Sets the IOT-2050 password")
-                .default_value(env!("DEFAULT_IOT_PASSWORD")),
-        )
-        .arg(
-            Arg::new("iot_host")
-                .short('a')
-                .long("iot-host")
-                .value_name("IOT_HOST")
-                .help("Sets the IOT-2050 host address and port")
-                .default_value(env!("DEFAULT_IOT_IP")),
-        )
-        .arg(
-            Arg::new("token")
-                .short('t')
-                .long("token")
-                .value_name("TOKEN_FOLDER")
-                .help("Sets the location of the InfluxDB token.txt")
-                .default_value(get_default_path().into_os_string()),
-        )
-        .arg(
-            Arg::new("send")
-                .short('s')
-                .long("send")
-                .action(ArgAction::SetTrue)
-                .help("Sends the existing telegraf.conf file to the IOT-2050 and quits"),
-        )
-        .arg(
-            Arg::new("backup_influx")
-            .short('b')
-            .long("backup-influx")
-            .action(ArgAction::SetTrue)
-            .help("Backs up the InfluxDB v2 database from the IOT-2050 and copies it to the current working directory"),
-        )
-        .arg(
-            Arg::new("backup_grafana")
-            .short('g')
-            .long("backup-grafana")
-            .action(ArgAction::SetTrue)
-            .help("Backs up the Grafana configuration from the IOT-2050 and copies it to the current working directory"),
-        )
-        .get_matches();
+fn ask(input: &str, instead: &str) {
+    // Stops a --no-input run right where it would have asked
+    if let Err(e) = output::ask(input, instead) {
+        eprintln!("Error: {}.", e);
+        wrap_up(1);
+    }
+}
 
-    // print the current config
-    print_config(&matches);
+fn silencing_flag() -> &'static str {
+    // The flag that keeps a run from asking, to name it in errors
+    if output::is_no_input() {
+        "--no-input"
+    } else {
+        "--quiet"
+    }
+}
 
-    let folder = matches.get_one::<String>("folder").unwrap();
-    let ip = matches.get_one::<String>("ip").unwrap();
-    let username = matches.get_one::<String>("username").unwrap();
-    let password = matches.get_one::<String>("password").unwrap();
-    let iot_password = matches.get_one::<String>("iot_password").unwrap();
-    let iot_host = matches.get_one::<String>("iot_host").unwrap();
-    let token_folder = matches.get_one::<String>("token").unwrap();
+fn explicit_arg<'a>(matches: &'a clap::ArgMatches, id: &str) -> Option<&'a String> {
+    // Returns an argument only when it was given on the command line or in the environment, not filled in from a default
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable) => matches.get_one::<String>(id),
+        _ => None,
+    }
+}
+
+fn explicit_values(matches: &clap::ArgMatches, id: &str) -> Vec<String> {
+    // Like explicit_arg, for flags that can be repeated
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable) => {
+            matches.get_many::<String>(id).unwrap().cloned().collect()
+        }
+        _ => Vec::new(),
+    }
+}
 
-    // Check if IP address is valid IPv4 format
-    let ip_valid = ip
-        .split('.')
-        .filter(|part| part.parse::<u8>().is_ok())
-        .count()
-        == 4;
+fn sink_values(
+    matches: &clap::ArgMatches,
+    id: &str,
+    sinks: usize,
+    base: Vec<String>,
+) -> Result<Vec<String>, String> {
+    // A flag given once applies to every sink, otherwise it needs one value per sink
+    let given = explicit_values(matches, id);
+    let values = if given.is_empty() { base } else { given };
+    match values.len() {
+        0 => Ok(vec![String::new(); sinks]),
+        1 => Ok(vec![values[0].clone(); sinks]),
+        count if count == sinks => Ok(values),
+        count => Err(format!(
+            "--{} is given {} times for {} InfluxDB sinks, give it once or once per --influx-url",
+            id.replace('_', "-"),
+            count,
+            sinks
+        )),
+    }
+}
 
-    if !ip_valid {
-        eprintln!(
-            "Error: Invalid IP address format for '{}', expecting something like: 192.168.0.1",
-            ip
-        );
+fn influx_outputs(
+    matches: &clap::ArgMatches,
+    base: &[format::InfluxOutput],
+) -> Vec<format::InfluxOutput> {
+    // Every --influx-url is a sink, flags that weren't given keep the values of the base outputs
+    let urls = explicit_values(matches, "influx_url");
+    let sinks = if urls.is_empty() {
+        base.len().max(1)
+    } else {
+        urls.len()
+    };
+    let base = if base.len() == sinks {
+        base
+    } else {
+        &base[..base.len().min(1)]
+    };
+    let field = |id: &str, value: fn(&format::InfluxOutput) -> &String| match sink_values(
+        matches,
+        id,
+        sinks,
+        base.iter().map(value).cloned().collect(),
+    ) {
+        Ok(values) => values,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            wrap_up(1);
+        }
+    };
+    let urls = field("influx_url", |output| &output.url);
+    // --token-file beats an INFLUX_TOKEN from the environment, but not --influx-token next to it
+    let token_files = !explicit_values(matches, "token_file").is_empty();
+    if token_files && matches.value_source("influx_token") == Some(ValueSource::CommandLine) {
+        eprintln!("Error: give the InfluxDB tokens with either --influx-token or --token-file.");
         wrap_up(1);
     }
+    let tokens = if !token_files {
+        field("influx_token", |output| &output.token)
+    } else {
+        // Given on the command line, so the base tokens are never taken as paths
+        field("token_file", |output| &output.token)
+            .iter()
+            .map(|path| read_token_file(path))
+            .collect()
+    };
+    let organizations = field("influx_org", |output| &output.organization);
+    let buckets = field("influx_bucket", |output| &output.bucket);
+
+    // A sink without a token would only fail once Telegraf tries to write
+    if prometheus_url(matches).is_none() {
+        if let Some(sink) = (0..sinks).find(|&sink| tokens[sink].is_empty()) {
+            eprintln!(
+                "Error: no InfluxDB token for {}, give it with --influx-token, --token-file or in token.txt.",
+                urls[sink]
+            );
+            wrap_up(1);
+        }
+        // A token pasted from the wrong place would only show once Telegraf fails to write
+        for (url, token) in urls.iter().zip(&tokens) {
+            if let Some(problem) = influx::token_problem(token) {
+                println!(
+                    "Warning: the InfluxDB token for {} {}, Telegraf will likely fail to write with it.",
+                    url, problem
+                );
+            }
+        }
+        if matches.get_flag("verify_token") {
+            verify_tokens(matches, &urls, &tokens);
+        }
+    } else if matches.get_flag("verify_token") {
+        println!("Warning: --verify-token only checks InfluxDB tokens, there are none with --output-type prometheus.");
+    }
+    (0..sinks)
+        .map(|sink| format::InfluxOutput {
+            url: urls[sink].clone(),
+            token: tokens[sink].clone(),
+            organization: organizations[sink].clone(),
+            bucket: buckets[sink].clone(),
+            bucket_tag: base.get(sink).is_some_and(|output| output.bucket_tag),
+        })
+        .collect()
+}
+
+fn verify_tokens(matches: &clap::ArgMatches, urls: &[String], tokens: &[String]) {
+    // InfluxDB on the box is asked over the network, the first host stands for all of them
+    let iot_host = iot_hosts(matches)
+        .first()
+        .and_then(|host| format::split_host_port(host))
+        .map(|(host, _)| host.to_string())
+        .unwrap_or_default();
+    for (url, token) in urls.iter().zip(tokens) {
+        let checked = influx::reachable_url(url, &iot_host);
+        say!("Checking the InfluxDB token on {} ..", checked);
+        if let Err(e) = influx::verify_token(&checked, token) {
+            eprintln!(
+                "Error: checking the InfluxDB token for {} failed: {}",
+                url, e
+            );
+            wrap_up(1);
+        }
+        say!("InfluxDB accepts the token for {}", url);
+    }
+}
+
+fn read_token_file(path: &str) -> String {
+    // The token is never printed, only where it came from
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            say!("InfluxDB token read from {}", path);
+            content.trim().to_string()
+        }
+        Err(e) => {
+            eprintln!(
+                "Error: unable to read the InfluxDB token from {}: {}",
+                path, e
+            );
+            wrap_up(1);
+        }
+    }
+}
+
+fn prometheus_url(matches: &clap::ArgMatches) -> Option<&str> {
+    // Only set when --output-type prometheus replaces the InfluxDB outputs
+    if matches.get_one::<String>("output_type").unwrap() != "prometheus" {
+        return None;
+    }
+    matches
+        .get_one::<String>("prometheus_url")
+        .map(String::as_str)
+}
 
-    // Check if IOT host IP address is valid
-    let iot_host_valid = {
-        let iot_host_parts: Vec<&str> = iot_host.split(':').collect();
-        iot_host_parts.len() == 2
-            && iot_host_parts[1]
-                .parse::<u16>()
-                .map_or(false, |port| port > 0)
+fn global_tags(
+    matches: &clap::ArgMatches,
+    imported: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    // --tag replaces the imported global tags as a whole, a key given twice keeps its last value
+    let Some(tags) = matches.get_many::<(String, String)>("tag") else {
+        return imported;
     };
+    let mut global_tags: Vec<(String, String)> = Vec::new();
+    for (key, value) in tags {
+        match global_tags.iter_mut().find(|(existing, _)| existing == key) {
+            Some(tag) => tag.1 = value.clone(),
+            None => global_tags.push((key.clone(), value.clone())),
+        }
+    }
+    global_tags
+}
+
+fn auth_method(matches: &clap::ArgMatches) -> format::AuthMethod {
+    // Builds the OPC UA auth method, certificate auth needs both key files unless --gen-cert fills them in
+    let key_files = matches.contains_id("certificate") && matches.contains_id("private_key");
+    match matches.get_one::<String>("opc_auth").unwrap().as_str() {
+        "anonymous" => format::AuthMethod::Anonymous,
+        "certificate" if !(key_files || matches.get_flag("gen_cert")) => {
+            eprintln!(
+                "Error: --opc-auth certificate needs --certificate and --private-key, or --gen-cert."
+            );
+            wrap_up(1);
+        }
+        "certificate" => format::AuthMethod::Certificate {
+            certificate: matches
+                .get_one::<String>("certificate")
+                .cloned()
+                .unwrap_or_default(),
+            private_key: matches
+                .get_one::<String>("private_key")
+                .cloned()
+                .unwrap_or_default(),
+        },
+        _ => format::AuthMethod::UserName,
+    }
+}
 
-    if !iot_host_valid {
+fn security_settings(policy: &str, mode: &str, mode_requested: bool) -> (String, String) {
+    // Without a security policy there is nothing to sign or encrypt with
+    if policy == "None" && mode != "None" {
+        if mode_requested {
+            println!(
+                "Warning: security mode '{}' needs a security policy, using 'None' because the policy is 'None'.",
+                mode
+            );
+        }
+        return (policy.to_string(), "None".to_string());
+    }
+    (policy.to_string(), mode.to_string())
+}
+
+fn check_credentials(matches: &clap::ArgMatches, opc: &format::OpcSettings) {
+    // The compiled-in credentials are meant for trying the tool out, not for a deployment
+    if matches.get_flag("allow_default_credentials") {
+        return;
+    }
+    let mut defaults = Vec::new();
+    if matches!(opc.auth, format::AuthMethod::UserName) {
+        if opc.username == env!("DEFAULT_USERNAME") {
+            defaults.push("OPC UA username");
+        }
+        if opc.password == env!("DEFAULT_PASSWORD") {
+            defaults.push("OPC UA password");
+        }
+    }
+    if matches.get_one::<String>("identity").is_none()
+        && matches.get_one::<String>("iot_password").unwrap() == env!("DEFAULT_IOT_PASSWORD")
+    {
+        defaults.push("IOT-2050 password");
+    }
+    if defaults.is_empty() {
+        return;
+    }
+    if matches.get_flag("strict") {
         eprintln!(
-            "Error: Invalid IOT host format for '{}', expecting something like: 192.168.0.1:22",
-            iot_host
+            "Error: the {}, set the credentials or add --allow-default-credentials.",
+            defaults_list(&defaults)
         );
         wrap_up(1);
     }
+    println!(
+        "Warning: the {}! Set the credentials before deploying, or add --allow-default-credentials to silence this.",
+        defaults_list(&defaults)
+    );
+}
 
-    let remote_path = "/etc/telegraf/telegraf.conf";
-    let iot_username = "root";
+fn defaults_list(defaults: &[&str]) -> String {
+    // "OPC UA username is still .." or "OPC UA username, OPC UA password are still .."
+    match defaults {
+        [single] => format!("{} is still the built-in default", single),
+        several => format!("{} are still the built-in defaults", several.join(", ")),
+    }
+}
 
-    // If the --send flag is set, attempt to only send the telegraf.conf file over SSH and restart Telegraf
-    if matches.get_flag("send") {
-        let config_path = Path::new(folder).join("telegraf.conf");
-        if !config_path.exists() {
-            eprintln!("Error: telegraf.conf file does not exist in the specified folder.");
+fn check_endpoint(matches: &clap::ArgMatches, opc: &format::OpcSettings) {
+    // --check-endpoint makes sure the server can be reached and has an endpoint the config can use before anything is generated
+    if !matches.get_flag("check_endpoint") {
+        return;
+    }
+    let endpoint = format::endpoint_url(&opc.ip);
+    say!(
+        "Checking the endpoints of the OPC UA server at {} ..",
+        endpoint
+    );
+    match opc_check::check_endpoint(opc, &endpoint) {
+        Ok(()) => report!(
+            "The OPC UA server at {} has an endpoint with the security policy, mode and user token type of the config.",
+            endpoint
+        ),
+        Err(e) => {
+            eprintln!("Error: endpoint check of {} failed: {}", endpoint, e);
             wrap_up(1);
         }
-        if let Err(e) = ssh_utils::send_and_restart_telegraf(
-            &config_path,
-            remote_path,
-            iot_host,
-            iot_username,
-            iot_password,
-        ) {
-            eprintln!(
-                "Failed to send telegraf.conf file and restart Telegraf: {}",
-                e
-            );
+    }
+}
+
+fn agent_settings(
+    matches: &clap::ArgMatches,
+    imported: Option<format::AgentSettings>,
+) -> format::AgentSettings {
+    let from_cli = format::AgentSettings {
+        interval: matches
+            .get_one::<String>("agent_interval")
+            .unwrap()
+            .to_string(),
+        flush_interval: matches
+            .get_one::<String>("flush_interval")
+            .unwrap()
+            .to_string(),
+        metric_batch_size: *matches.get_one::<u32>("batch_size").unwrap(),
+        metric_buffer_limit: *matches.get_one::<u32>("buffer_limit").unwrap(),
+    };
+    let imported = match imported {
+        Some(imported) => imported,
+        None => return from_cli,
+    };
+
+    // Flags given on the command line retarget the imported agent settings
+    let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    format::AgentSettings {
+        interval: if explicit("agent_interval") {
+            from_cli.interval
+        } else {
+            imported.interval
+        },
+        flush_interval: if explicit("flush_interval") {
+            from_cli.flush_interval
+        } else {
+            imported.flush_interval
+        },
+        metric_batch_size: if explicit("batch_size") {
+            from_cli.metric_batch_size
+        } else {
+            imported.metric_batch_size
+        },
+        metric_buffer_limit: if explicit("buffer_limit") {
+            from_cli.metric_buffer_limit
+        } else {
+            imported.metric_buffer_limit
+        },
+    }
+}
+
+fn print_config_diff(remote_path: &Path, local_path: &Path) {
+    // Unified diff from the config on the box to the one that would be sent
+    let (remote, local) = match (
+        fs::read_to_string(remote_path),
+        fs::read_to_string(local_path),
+    ) {
+        (Ok(remote), Ok(local)) => (remote, local),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to read the configs for comparison: {}", e);
+            return;
+        }
+    };
+    if remote == local {
+        println!("{} matches the remote config.", local_path.display());
+        return;
+    }
+    let diff = similar::TextDiff::from_lines(&remote, &local);
+    print!(
+        "{}",
+        diff.unified_diff().header(
+            &remote_path.display().to_string(),
+            &local_path.display().to_string()
+        )
+    );
+}
+
+fn read_password(matches: &clap::ArgMatches, id: &str, prompt: &str) -> String {
+    // Prompts without echo unless the password was given on the command line, enter keeps the default
+    if let Some(password) = explicit_arg(matches, id) {
+        return password.to_string();
+    }
+    let default = matches.get_one::<String>(id);
+    ask(
+        &format!("the {}", prompt),
+        &format!(
+            "pass --{} or set it in the environment",
+            id.replace('_', "-")
+        ),
+    );
+    let question = match default {
+        Some(_) => format!("{} (press enter for the default): ", prompt),
+        None => format!("{}: ", prompt),
+    };
+    let entered = if io::stdin().is_terminal() {
+        rpassword::prompt_password(question)
+    } else {
+        // Piped input has nothing to hide, read it as a plain line
+        if !output::is_quiet() {
+            print!("{}", question);
+            io::stdout().flush().unwrap();
+        }
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map(|_| line.trim_end_matches(['\r', '\n']).to_string())
+    };
+    match entered {
+        Ok(password) if !password.is_empty() => password,
+        Ok(_) => match default {
+            Some(default) => default.to_string(),
+            None => {
+                eprintln!("Error: no {} given.", prompt);
+                wrap_up(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to read the password: {}", e);
             wrap_up(1);
         }
-        wrap_up(0);
     }
+}
+
+fn iot_hosts(matches: &clap::ArgMatches) -> Vec<&str> {
+    matches
+        .get_many::<String>("iot_host")
+        .unwrap()
+        .map(String::as_str)
+        .collect()
+}
+
+fn ssh_targets(matches: &clap::ArgMatches) -> Vec<ssh_utils::SshTarget> {
+    // The boxes are set up identically, so they share the root account and a single password or key
+    let target = ssh_utils::SshTarget {
+        host: String::new(),
+        username: "root".to_string(),
+        // Key-based logins don't need the password, so don't ask for it
+        password: match matches.get_one::<String>("identity") {
+            Some(_) => String::new(),
+            None => read_password(matches, "iot_password", "IOT-2050 password"),
+        },
+        identity: matches.get_one::<String>("identity").map(PathBuf::from),
+        insecure: matches.get_flag("insecure"),
+        timeout: Duration::from_secs(*matches.get_one::<u64>("ssh_timeout").unwrap()),
+        retries: *matches.get_one::<u32>("ssh_retries").unwrap(),
+        sftp: matches.get_one::<String>("transfer_method").unwrap() == "sftp",
+    };
+    iot_hosts(matches)
+        .into_iter()
+        .map(|host| ssh_utils::SshTarget {
+            host: host.to_string(),
+            ..target.clone()
+        })
+        .collect()
+}
+
+fn host_directory(target: &ssh_utils::SshTarget, targets: &[ssh_utils::SshTarget]) -> PathBuf {
+    // Downloads from several hosts go into one folder per host so they don't overwrite each other
+    if targets.len() > 1 {
+        PathBuf::from(format::sanitize_name(&target.host))
+    } else {
+        PathBuf::from(".")
+    }
+}
+
+/// How a deployment goes through the hosts, from --concurrency, --host-retries and --max-failure-rate.
+struct Rollout {
+    concurrency: usize,
+    /// How often a failed host is tried again before it counts as failed.
+    retries: u32,
+    /// The percentage of all hosts that may fail before the remaining ones are skipped.
+    max_failure_rate: Option<u32>,
+}
+
+fn rollout(matches: &clap::ArgMatches) -> Rollout {
+    Rollout {
+        concurrency: *matches.get_one::<u32>("concurrency").unwrap() as usize,
+        retries: *matches.get_one::<u32>("host_retries").unwrap(),
+        max_failure_rate: matches.get_one::<u32>("max_failure_rate").copied(),
+    }
+}
+
+fn one_at_a_time(matches: &clap::ArgMatches) -> Rollout {
+    // Rollbacks prompt for the backup to use and downloads share the local link, so those take the hosts in turn
+    Rollout {
+        concurrency: 1,
+        ..rollout(matches)
+    }
+}
 
-    // Check if the backup flag is set and perform backup if true
-    if matches.get_flag("backup_influx") {
-        if let Err(e) = ssh_utils::backup_influxdb(iot_host, iot_username, iot_password) {
-            eprintln!("Failed to backup InfluxDB: {}", e);
+fn run_with_retries(
+    target: &ssh_utils::SshTarget,
+    action: &str,
+    retries: u32,
+    run: &impl Fn(&ssh_utils::SshTarget) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), String> {
+    // A box on a flaky link often succeeds on the next try, the whole operation is repeated.
+    // Only a box that couldn't be reached is tried again: a rejected login won't fix itself, and
+    // a send that failed halfway may already have replaced the config or restarted Telegraf.
+    let mut delay = Duration::from_secs(2);
+    let mut attempt = 0;
+    loop {
+        log::info!("Starting to {} on {}", action, target.host);
+        let result = run(target);
+        log::info!(
+            "Finished on {}: {}",
+            target.host,
+            if result.is_ok() { "ok" } else { "failed" }
+        );
+        match result {
+            Err(e) if attempt < retries && e.is::<ssh_utils::Unreachable>() => {
+                attempt += 1;
+                ereport!(
+                    "Failed to {}: {}, trying again in {}s (retry {} of {}) ..",
+                    action,
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => {
+                ereport!("Failed to {}: {}", action, e);
+                return Err(e.to_string());
+            }
+            Ok(()) => return Ok(()),
         }
-        wrap_up(0);
     }
+}
 
-    //check if the -g flag is set and perform backup if true
-    if matches.get_flag("backup_grafana") {
-        let iot_host = matches.get_one::<String>("iot_host").unwrap();
-        let iot_password = matches.get_one::<String>("iot_password").unwrap();
-        match ssh_utils::backup_grafana_config(iot_host, "root", iot_password) {
-            Ok(_) => println!("Grafana configuration backup completed successfully."),
-            Err(e) => eprintln!("Failed to backup Grafana configuration: {}", e),
+fn for_each_host_parallel(
+    targets: &[ssh_utils::SshTarget],
+    action: &str,
+    rollout: &Rollout,
+    run: impl Fn(&ssh_utils::SshTarget) -> Result<(), Box<dyn std::error::Error>> + Sync,
+) -> bool {
+    // Runs an operation on up to `concurrency` hosts at once, printing each host's output as one block.
+    // Once more hosts failed than --max-failure-rate allows, something systemic is most likely wrong
+    // and the hosts not started yet are skipped.
+    let failed = AtomicUsize::new(0);
+    let tripped = |failed: usize| {
+        rollout
+            .max_failure_rate
+            .is_some_and(|rate| failed * 100 > rate as usize * targets.len())
+    };
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; targets.len()]);
+    let captured = rollout.concurrency > 1 && targets.len() > 1;
+    let work = || loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        let Some(target) = targets.get(index) else {
+            break;
+        };
+        if tripped(failed.load(Ordering::SeqCst)) {
+            log::info!("Skipping {}, the rollout was stopped", target.host);
+            continue;
         }
-        wrap_up(0);
+        let attempt = || {
+            if targets.len() > 1 {
+                say!("\n==> {}", target.host);
+            }
+            run_with_retries(target, action, rollout.retries, &run)
+        };
+        let result = if captured {
+            let (result, lines) = output::captured(attempt);
+            output::print_captured(&lines);
+            result
+        } else {
+            attempt()
+        };
+        if result.is_err() {
+            let failed = failed.fetch_add(1, Ordering::SeqCst) + 1;
+            if tripped(failed) && !tripped(failed - 1) {
+                ereport!(
+                    "{} of {} hosts failed, more than --max-failure-rate {}% allows, skipping the hosts not started yet.",
+                    failed,
+                    targets.len(),
+                    rollout.max_failure_rate.unwrap_or_default()
+                );
+            }
+        }
+        results.lock().unwrap()[index] = Some(result);
+    };
+    if captured {
+        std::thread::scope(|scope| {
+            for _ in 0..rollout.concurrency.min(targets.len()) {
+                scope.spawn(work);
+            }
+        });
+    } else {
+        work();
+    }
+    print_host_summary(targets, &results.into_inner().unwrap())
+}
+
+fn print_host_summary(
+    targets: &[ssh_utils::SshTarget],
+    results: &[Option<Result<(), String>>],
+) -> bool {
+    // Lists how every host fared when there is more than one, and tells whether all succeeded.
+    // Hosts without a result were skipped once the rollout was stopped.
+    if targets.len() > 1 {
+        let width = targets
+            .iter()
+            .map(|target| target.host.len())
+            .max()
+            .unwrap_or(0);
+        println!("\nSummary:");
+        println!("  {:<width$}  Status", "Host", width = width);
+        for (target, result) in targets.iter().zip(results) {
+            let status = match result {
+                Some(Ok(())) => "ok".to_string(),
+                Some(Err(e)) => format!("failed: {}", e),
+                None => "skipped, too many hosts failed".to_string(),
+            };
+            println!("  {:<width$}  {}", target.host, status, width = width);
+        }
+        let count = |wanted: fn(&Option<Result<(), String>>) -> bool| {
+            results.iter().filter(|result| wanted(result)).count()
+        };
+        println!(
+            "  {} succeeded, {} failed, {} skipped",
+            count(|result| matches!(result, Some(Ok(())))),
+            count(|result| matches!(result, Some(Err(_)))),
+            count(Option::is_none)
+        );
     }
+    results.iter().all(|result| matches!(result, Some(Ok(()))))
+}
 
-    let xml_files: Vec<String> = fs::read_dir(folder)
+fn find_xml_files(folder: &str) -> Vec<String> {
+    // A mistyped --folder is the first thing new users hit, so explain it instead of panicking
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(e) => {
+            match e.kind() {
+                io::ErrorKind::NotFound => {
+                    eprintln!("Error: the folder '{}' does not exist.", folder)
+                }
+                io::ErrorKind::PermissionDenied => {
+                    eprintln!("Error: permission denied reading the folder '{}'.", folder)
+                }
+                _ if !Path::new(folder).is_dir() => {
+                    eprintln!("Error: '{}' is not a folder.", folder)
+                }
+                _ => eprintln!("Error: unable to read the folder '{}': {}", folder, e),
+            }
+            wrap_up(1);
+        }
+    };
+    let xml_files: Vec<String> = entries
         // Collect all XML files from the specified folder for processing
-        .unwrap()
         .filter_map(|entry| {
-            let path = entry.unwrap().path();
-            if path.is_file() && path.extension().map_or(false, |ext| ext == "xml") {
+            let path = entry.ok()?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "xml") {
                 Some(path.to_str().unwrap().to_string())
             } else {
                 None
@@ -232,50 +745,135 @@ fn main() {
 
     if !xml_files.is_empty() {
         // Notify the user about the found XML files and ask for confirmation to proceed
-        println!("{}", "Found the following XML files in the folder:");
+        say!("Found the following XML files in the folder:");
         for (index, file) in xml_files.iter().enumerate() {
-            println!("{}. {}", index + 1, file);
+            say!("{}. {}", index + 1, file);
         }
     } else {
-        println!("{}", "No XML files found in the folder.");
-        println!("{}", "This is clearly your fault, not mine..");
+        println!("No XML files found in the folder.");
+        say!("This is clearly your fault, not mine..");
 
-        if cfg!(target_os = "windows") {
+        if cfg!(target_os = "windows") && !output::is_quiet() && !output::is_no_input() {
             println!("Press enter to exit");
             io::stdout().flush().unwrap();
             let _ = io::stdin().read(&mut [0]).unwrap();
         }
 
-        println!("{}", "Aborting.");
+        println!("Aborting.");
         wrap_up(1);
     }
 
-    println!("");
-    println!("{}", "Do you want to use these files? (y/N)");
-    let mut confirm = String::new();
-    std::io::stdin().read_line(&mut confirm).unwrap();
+    xml_files
+}
 
-    if confirm.trim().to_lowercase() != "y" {
-        println!("Aborting.");
-        wrap_up(1);
+fn csv_field(field: &str) -> String {
+    // Quotes fields that would otherwise split the row, VariableMappings often contain `;` and `"`
+    if field.contains([',', '"', '\n', ';']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn list_nodes(matches: &clap::ArgMatches, folder: &str) -> ! {
+    // Prints the variables of every XML file in the folder and stops before anything is asked
+    let csv = matches.get_one::<String>("format").unwrap() == "csv";
+    let mut failed = false;
+    if csv {
+        println!("file,node_id,browse_name,variable_mapping,data_type");
+    }
+    for xml_file in find_xml_files(folder) {
+        let nodes = match format::list_nodes(&xml_file) {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                failed = true;
+                continue;
+            }
+        };
+        if csv {
+            for node in &nodes {
+                let fields = [
+                    xml_file.as_str(),
+                    &node.node_id,
+                    &node.browse_name,
+                    &node.variable_mapping,
+                    &node.data_type,
+                ];
+                let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                println!("{}", row.join(","));
+            }
+            continue;
+        }
+
+        let header = ["NodeId", "BrowseName", "VariableMapping", "DataType"];
+        let rows: Vec<[&str; 4]> = nodes
+            .iter()
+            .map(|node| {
+                [
+                    node.node_id.as_str(),
+                    &node.browse_name,
+                    &node.variable_mapping,
+                    &node.data_type,
+                ]
+            })
+            .collect();
+        let mut widths = header.map(str::len);
+        for row in &rows {
+            for (width, field) in widths.iter_mut().zip(row) {
+                *width = (*width).max(field.chars().count());
+            }
+        }
+        println!();
+        println!("{} ({} variables):", xml_file, rows.len());
+        for row in std::iter::once(&header).chain(&rows) {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(field, width)| format!("{:<width$}", field, width = width))
+                .collect();
+            println!("  {}", line.join("  ").trim_end());
+        }
+    }
+    wrap_up(if failed { 1 } else { 0 });
+}
+
+fn parse_listener_selection(selection: &str, file_count: usize) -> Result<Vec<usize>, String> {
+    // Turns "all", "none" or 1-based indexes like "1,3" into 0-based file indexes, empty means none
+    match selection.trim().to_lowercase().as_str() {
+        "all" => return Ok((0..file_count).collect()),
+        "none" | "" => return Ok(Vec::new()),
+        _ => {}
     }
-    println!("{}","OPC clients can be active (standard), pulling data every interval, or \npassive (subscribers), listening for changes.");
-    //println!("");
-    println!("{}","Enter the indexes of the files that should be listeners (subscribers), \nseparated by commas (e.g., 1,3). If none, just press enter:");
-    let mut listener_numbers = String::new();
-    std::io::stdin().read_line(&mut listener_numbers).unwrap();
-    let listener_indices: Vec<usize> = listener_numbers
-        .trim()
+    // Every entry that isn't a file index is collected, so one message lists all the typos
+    let mut indices = Vec::new();
+    let mut rejected = Vec::new();
+    for part in selection
         .split(',')
-        .filter_map(|num| num.trim().parse::<usize>().ok())
-        .filter(|&num| num > 0 && num <= xml_files.len())
-        .map(|num| num - 1) // Convert to 0-based index
-        .collect();
-    let listener_files: Vec<String> = listener_indices
-        .iter()
-        .map(|&index| xml_files[index].clone())
-        .collect();
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        match part.parse::<usize>() {
+            Ok(index) if index > 0 && index <= file_count => indices.push(index - 1),
+            _ => rejected.push(format!("'{}'", part)),
+        }
+    }
+    if !rejected.is_empty() {
+        return Err(format!(
+            "{} {}, expecting all, none or numbers from 1 to {}",
+            rejected.join(", "),
+            if rejected.len() == 1 {
+                "is not a file index"
+            } else {
+                "are not file indexes"
+            },
+            file_count
+        ));
+    }
+    Ok(indices)
+}
 
+fn read_influx_token(token_folder: &str) -> String {
     let mut influx_token = String::new();
     // Attempt to read the InfluxDB token from a file, or ask the user to input it
     let token_file_path = Path::new(token_folder).join("token.txt");
@@ -283,7 +881,7 @@ fn main() {
         match std::fs::read_to_string(&token_file_path) {
             Ok(content) => {
                 influx_token = content.trim().to_string();
-                println!(
+                say!(
                     "InfluxDB token read from {}",
                     token_file_path.to_string_lossy()
                 );
@@ -298,10 +896,11 @@ fn main() {
             }
         }
     } else {
-        println!(
-            "{}",
-            "No 'token.txt' found, enter the InfluxDB token manually:"
+        ask(
+            "the InfluxDB token",
+            "pass --influx-token or --token-file, or put a token.txt into the --token folder",
         );
+        say!("No 'token.txt' found, enter the InfluxDB token manually:");
         match std::io::stdin().read_line(&mut influx_token) {
             Ok(_) => {
                 influx_token = influx_token.trim().to_string();
@@ -312,57 +911,1230 @@ fn main() {
             }
         }
     }
+    influx_token
+}
+
+fn config_path(matches: &clap::ArgMatches) -> PathBuf {
+    // Configs live at --output when given, otherwise as telegraf.conf in the folder
+    match matches.get_one::<String>("output") {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(matches.get_one::<String>("folder").unwrap()).join("telegraf.conf"),
+    }
+}
 
-    let mut config_strings = Vec::new();
-    // Generate configuration strings for each XML file, checking whether it's a listener
-    for file in &xml_files {
-        let is_listener = listener_files.contains(file);
-        let config_string = format::parse_xml(file, ip, username, password, is_listener);
-        config_strings.push(config_string);
+fn restart_command(matches: &clap::ArgMatches) -> ssh_utils::RestartCommand {
+    // A restart that doesn't go through systemd can't be checked with systemctl either
+    let restart = matches
+        .get_one::<String>("restart_command")
+        .unwrap()
+        .to_string();
+    let status = match explicit_arg(matches, "status_command") {
+        Some(status) => Some(status.to_string()),
+        None if restart.contains("systemctl") => {
+            matches.get_one::<String>("status_command").cloned()
+        }
+        None => None,
+    };
+    ssh_utils::RestartCommand {
+        restart,
+        status,
+        log_lines: *matches.get_one::<u32>("log_lines").unwrap(),
+        verbose: matches.get_count("verbose") > 0,
+        timeout: Duration::from_secs(*matches.get_one::<u64>("restart_timeout").unwrap()),
     }
+}
 
-    // Combine all configuration strings into the final config file content
-    let config_content = format::generate_config_content(&influx_token, &config_strings);
+fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    // Writes next to the target and renames it over, so an interrupted write leaves the old file whole
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.tmp-{}", file_name, std::process::id()));
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(content.as_bytes())?;
+        // Keeps the permissions of the file being replaced, as writing over it would
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()
+    });
+    let renamed = written.and_then(|_| fs::rename(&temp_path, path));
+    if renamed.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    renamed
+}
 
-    // Write the config file to the folder
-    let config_path = Path::new(folder).join("telegraf.conf");
-    let mut config_file = File::create(&config_path).unwrap();
-    config_file.write_all(config_content.as_bytes()).unwrap();
+fn remote_path(matches: &clap::ArgMatches) -> &str {
+    // /etc/telegraf/telegraf.conf unless Telegraf loads its config from elsewhere, like a telegraf.d drop-in
+    matches.get_one::<String>("remote_path").unwrap()
+}
 
-    println!("{}", "Config file generated successfully!");
+fn send_to_hosts(matches: &clap::ArgMatches, config_path: &Path) -> bool {
+    // Pushes the config to every host and restarts Telegraf there
+    if !config_path.exists() {
+        eprintln!("Error: {} does not exist.", config_path.display());
+        wrap_up(1);
+    }
+    let targets = ssh_targets(matches);
+    let restart = restart_command(matches);
+    for_each_host_parallel(
+        &targets,
+        "send telegraf.conf file and restart Telegraf",
+        &rollout(matches),
+        |target| {
+            ssh_utils::send_and_restart_telegraf(
+                config_path,
+                remote_path(matches),
+                target,
+                !matches.get_flag("no_backup"),
+                &restart,
+            )
+        },
+    )
+}
 
-    // Ask the user if they want to automatically send the generated config file to the IOT box
-    println!(
-        "{}",
-        "Do you want to send the config file to the IOT box? (y/N)"
+fn file_status(
+    parsed: &Result<Vec<format::OpcGroup>, Box<dyn std::error::Error>>,
+    is_listener: bool,
+    verbose: bool,
+) -> String {
+    // One line per file, so a file that gave unexpectedly few nodes stands out during the run
+    let Ok(groups) = parsed else {
+        return "failed".to_string();
+    };
+    let mut status = format!(
+        "{} nodes, {}",
+        groups.iter().map(|group| group.nodes.len()).sum::<usize>(),
+        if is_listener { "listener" } else { "standard" }
     );
+    if verbose {
+        for group in groups {
+            status.push_str(&format!(
+                ", group '{}' in namespace '{}'",
+                group.name, group.namespace
+            ));
+        }
+    }
+    status
+}
 
-    let mut user_input = String::new();
-    std::io::stdin().read_line(&mut user_input).unwrap();
-    if user_input.trim().eq_ignore_ascii_case("y") {
-        let config_path = Path::new(folder).join("telegraf.conf");
-        if !config_path.exists() {
-            eprintln!("Error: telegraf.conf file does not exist in the specified folder.");
-            wrap_up(1);
+fn choose_backup(backups: &[String], newest: bool) -> usize {
+    // Lists the backups newest first and asks which one to restore, Enter takes the newest
+    if newest {
+        return 0;
+    }
+    println!("Backups found:");
+    for (index, backup) in backups.iter().enumerate() {
+        println!("  {}. {}", index + 1, backup);
+    }
+    loop {
+        println!("Enter the number of the backup to restore (default 1):");
+        let mut choice = String::new();
+        std::io::stdin().read_line(&mut choice).unwrap();
+        if choice.trim().is_empty() {
+            return 0;
         }
-        if let Err(e) = ssh_utils::send_and_restart_telegraf(
-            &config_path,
-            remote_path,
-            iot_host,
-            iot_username,
-            iot_password,
-        ) {
-            eprintln!(
-                "Failed to send telegraf.conf file and restart Telegraf: {}",
-                e
-            );
+        match choice.trim().parse::<usize>() {
+            Ok(number) if (1..=backups.len()).contains(&number) => return number - 1,
+            _ => println!(
+                "Warning: '{}' is not a number between 1 and {}, please try again.",
+                choice.trim(),
+                backups.len()
+            ),
         }
-        wrap_up(1);
-    } else {
-        println!(
-            "{}",
-            "Config file generated. Please copy it and run telegraf manually."
+    }
+}
+
+fn rollback_config(matches: &clap::ArgMatches) -> ! {
+    // Put a previous config back on every host, one after the other since each may ask
+    let targets = ssh_targets(matches);
+    let restart = restart_command(matches);
+    let newest = matches.get_flag("yes");
+    if !newest {
+        ask(
+            "the backup to restore",
+            "add --yes to restore the newest one",
+        );
+    }
+    if (output::is_quiet() || output::is_no_input()) && !newest {
+        eprintln!(
+            "Error: {} can't ask which backup to restore, add --yes.",
+            silencing_flag()
+        );
+        wrap_up(1);
+    }
+    let rolled_back = for_each_host_parallel(
+        &targets,
+        "roll back telegraf.conf",
+        &one_at_a_time(matches),
+        |target| {
+            ssh_utils::rollback_telegraf_config(
+                remote_path(matches),
+                target,
+                |backups| choose_backup(backups, newest),
+                &restart,
+            )
+        },
+    );
+    wrap_up(if rolled_back { 0 } else { 1 });
+}
+
+fn send_config(matches: &clap::ArgMatches) -> ! {
+    if matches.get_flag("rollback") {
+        rollback_config(matches);
+    }
+    let sent = send_to_hosts(matches, &config_path(matches));
+    wrap_up(if sent { 0 } else { 1 });
+}
+
+fn send_client_certificate(matches: &clap::ArgMatches, files: &(PathBuf, PathBuf)) -> bool {
+    // Uploads a --gen-cert pair before the config that refers to it
+    let (certificate, private_key) = files;
+    for_each_host_parallel(
+        &ssh_targets(matches),
+        "send the client certificate",
+        &rollout(matches),
+        |target| {
+            ssh_utils::send_file_over_ssh(certificate, REMOTE_CERTIFICATE_PATH, target, 0o644)?;
+            ssh_utils::send_private_key_over_ssh(private_key, REMOTE_PRIVATE_KEY_PATH, target)?;
+            say!("Sent the client certificate to {}", target.host);
+            Ok(())
+        },
+    )
+}
+
+fn send_file(matches: &clap::ArgMatches) -> ! {
+    // Push any file to the box as is, without touching Telegraf
+    let local = Path::new(matches.get_one::<String>("local").unwrap());
+    let remote = matches.get_one::<String>("remote").unwrap();
+    let mode = *matches.get_one::<i32>("mode").unwrap();
+    if !local.is_file() {
+        eprintln!("Error: {} is not a file.", local.display());
+        wrap_up(1);
+    }
+    let targets = ssh_targets(matches);
+    let sent = for_each_host_parallel(
+        &targets,
+        &format!("send {}", local.display()),
+        &rollout(matches),
+        |target| {
+            ssh_utils::send_file_over_ssh(local, remote, target, mode)?;
+            report!("Sent {} to {} on {}", local.display(), remote, target.host);
+            Ok(())
+        },
+    );
+    wrap_up(if sent { 0 } else { 1 });
+}
+
+fn pull_config(matches: &clap::ArgMatches) -> ! {
+    // Fetch the config that is currently on the box and compare it with the local one
+    let pull_path = matches.get_one::<String>("path").unwrap();
+    let config_path = config_path(matches);
+    let targets = ssh_targets(matches);
+    let pulled = for_each_host_parallel(
+        &targets,
+        "download the remote telegraf.conf",
+        &one_at_a_time(matches),
+        |target| {
+            let pull_path = if targets.len() > 1 {
+                PathBuf::from(format!(
+                    "{}.{}",
+                    pull_path,
+                    format::sanitize_name(&target.host)
+                ))
+            } else {
+                PathBuf::from(pull_path)
+            };
+            ssh_utils::pull_telegraf_config(remote_path(matches), &pull_path, target)?;
+            if config_path.exists() {
+                print_config_diff(&pull_path, &config_path);
+            }
+            Ok(())
+        },
+    );
+    wrap_up(if pulled { 0 } else { 1 });
+}
+
+fn diff_configs(matches: &clap::ArgMatches) -> ! {
+    // Compare two configs by their groups and nodes, which a line diff buries in reformatting
+    let read = |id: &str| {
+        let path = matches.get_one::<String>(id).unwrap();
+        match import::import_config(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error: unable to read {}: {}", path, e);
+                wrap_up(1);
+            }
+        }
+    };
+    let (old, new) = (read("old"), read("new"));
+    let changes = diff::diff_configs(&old, &new);
+    if changes.is_empty() {
+        report!("No differences in the OPC UA groups, nodes or settings.");
+    }
+    for change in &changes {
+        report!("{}", change);
+    }
+    wrap_up(0);
+}
+
+fn backup_influx(matches: &clap::ArgMatches) -> ! {
+    let targets = ssh_targets(matches);
+    let backed_up = for_each_host_parallel(
+        &targets,
+        "backup InfluxDB",
+        &one_at_a_time(matches),
+        |target| {
+            ssh_utils::backup_influxdb(
+                target,
+                &host_directory(target, &targets),
+                !matches.get_flag("no_compress"),
+                matches.get_flag("extract"),
+                matches.get_one::<u32>("keep").copied(),
+                matches.get_flag("force"),
+                matches.get_one::<u64>("max_rate").copied(),
+                matches
+                    .get_one::<String>("backup_since")
+                    .map(String::as_str),
+            )
+        },
+    );
+    wrap_up(if backed_up { 0 } else { 1 });
+}
+
+fn backup_grafana(matches: &clap::ArgMatches) -> ! {
+    let targets = ssh_targets(matches);
+    let dashboards = matches.get_flag("dashboards");
+    let token = if dashboards {
+        read_password(matches, "grafana_token", "Grafana API token")
+    } else {
+        String::new()
+    };
+    let backed_up = for_each_host_parallel(
+        &targets,
+        "backup Grafana configuration",
+        &one_at_a_time(matches),
+        |target| {
+            let local_directory = host_directory(target, &targets);
+            ssh_utils::backup_grafana_config(target, &local_directory)?;
+            say!("Grafana configuration backup completed successfully.");
+            if dashboards {
+                // Grafana runs on the box itself unless told otherwise
+                let grafana_url = match matches.get_one::<String>("grafana_url") {
+                    Some(grafana_url) => grafana_url.to_string(),
+                    None => {
+                        let host = format::split_host_port(&target.host)
+                            .map_or(target.host.as_str(), |(host, _)| host);
+                        format!("http://{}:3000", host)
+                    }
+                };
+                let date = chrono::Utc::now().format("%Y-%m-%d").to_string();
+                grafana::backup_grafana_dashboards(
+                    &grafana_url,
+                    &token,
+                    &local_directory.join(format!("grafana_dashboards_{}", date)),
+                )?;
+            }
+            Ok(())
+        },
+    );
+    wrap_up(if backed_up { 0 } else { 1 });
+}
+
+fn restore_influx(matches: &clap::ArgMatches) -> ! {
+    let backup = Path::new(matches.get_one::<String>("backup").unwrap());
+    if !backup.exists() {
+        eprintln!("Error: {} does not exist.", backup.display());
+        wrap_up(1);
+    }
+    let targets = ssh_targets(matches);
+    let restored = for_each_host_parallel(
+        &targets,
+        "restore InfluxDB",
+        &one_at_a_time(matches),
+        |target| ssh_utils::restore_influxdb(target, backup, matches.get_flag("full")),
+    );
+    wrap_up(if restored { 0 } else { 1 });
+}
+
+fn print_summary(matches: &clap::ArgMatches, summary: &summary::Summary) {
+    // Emitted after everything else so a pipeline can capture it from the end of stdout
+    if let Some(destination) = matches.get_one::<String>("json_summary") {
+        if let Err(e) = summary::write_summary(summary, destination) {
+            eprintln!("Failed to write the JSON summary to {}: {}", destination, e);
+        }
+    }
+}
+
+fn generate(matches: &clap::ArgMatches) -> ! {
+    // Builds a config from XML files or an imported telegraf.conf, writes it and offers to send it
+    let folder = matches.get_one::<String>("folder").unwrap();
+    let ip = matches.get_one::<String>("ip").unwrap();
+    let username = matches.get_one::<String>("username").unwrap();
+    let token_folder = matches.get_one::<String>("token").unwrap();
+
+    if matches.get_flag("list_nodes") {
+        list_nodes(matches, folder);
+    }
+
+    // Check that the OPC UA server is an IPv4 or IPv6 address or a hostname
+    if !format::is_valid_host(ip) {
+        eprintln!(
+            "Error: Invalid OPC UA server address '{}', expecting something like: 192.168.0.1, fe80::1 or opc.example.local",
+            ip
+        );
+        wrap_up(1);
+    }
+
+    // Check that the InfluxDB or Prometheus URLs are something Telegraf can write to
+    if let Some(prometheus_url) = prometheus_url(matches) {
+        if !format::is_valid_http_url(prometheus_url) {
+            eprintln!(
+                "Error: Invalid Prometheus URL '{}', expecting something like: http://127.0.0.1:9090/api/v1/write",
+                prometheus_url
+            );
+            wrap_up(1);
+        }
+    }
+    for influx_url in matches.get_many::<String>("influx_url").unwrap() {
+        if !format::is_valid_http_url(influx_url) {
+            eprintln!(
+                "Error: Invalid InfluxDB URL '{}', expecting something like: http://127.0.0.1:8086",
+                influx_url
+            );
+            wrap_up(1);
+        }
+    }
+
+    // Telegraf refuses to start on durations it can't parse
+    for id in [
+        "agent_interval",
+        "flush_interval",
+        "connect_timeout",
+        "request_timeout",
+        "session_timeout",
+        "publishing_interval",
+    ] {
+        let Some(duration) = matches.get_one::<String>(id) else {
+            continue;
+        };
+        if !format::is_valid_duration(duration) {
+            eprintln!(
+                "Error: Invalid duration '{}' for --{}, expecting something like: 10s or 500ms",
+                duration,
+                id.replace('_', "-")
+            );
+            wrap_up(1);
+        }
+    }
+
+    let config_path = config_path(matches);
+    // Another run writing into the same folder would leave a mix of both configs
+    if !matches.get_flag("dry_run") && !matches.get_flag("validate_only") {
+        let directory = config_path
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if let Err(e) = lock::acquire(directory, matches.get_flag("force")) {
+            eprintln!("Error: {}.", e);
+            wrap_up(1);
+        }
+    }
+    let append = matches.get_flag("append_inputs");
+    if append && !config_path.exists() {
+        eprintln!(
+            "Error: --append-inputs needs an existing config, {} does not exist.",
+            config_path.display()
+        );
+        wrap_up(1);
+    }
+
+    // Generated before any question is asked, so a missing openssl doesn't waste the answers
+    let client_certificate = matches.get_flag("gen_cert").then(|| {
+        let directory = config_path.parent().unwrap_or(Path::new("."));
+        // Runs that write nothing only need to know where the pair would go
+        if matches.get_flag("dry_run") || matches.get_flag("validate_only") {
+            say!("Not generating a client certificate, nothing is written in this run.");
+            return cert::certificate_paths(directory);
+        }
+        match cert::client_certificate(directory) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Error: unable to generate a client certificate: {}", e);
+                wrap_up(1);
+            }
+        }
+    });
+
+    // Counted while reading the XML files, for --json-summary
+    let mut xml_file_count = 0;
+    let mut skipped_files = Vec::new();
+    let (mut groups, agent, mut outputs, mut opc, global_tags) = if let Some(import_path) =
+        matches.get_one::<String>("import")
+    {
+        // Regenerate from an existing config instead of XML files
+        let imported = match import::import_config(import_path) {
+            Ok(imported) => imported,
+            Err(e) => {
+                eprintln!("Failed to import {}: {}", import_path, e);
+                wrap_up(1);
+            }
+        };
+        say!(
+            "Imported {} groups from {}",
+            imported.groups.len(),
+            import_path
+        );
+
+        // Flags given on the command line retarget the imported settings
+        let imported_opc = imported.opc;
+        let (security_policy, security_mode) = security_settings(
+            explicit_arg(matches, "security_policy").unwrap_or(&imported_opc.security_policy),
+            explicit_arg(matches, "security_mode").unwrap_or(&imported_opc.security_mode),
+            explicit_arg(matches, "security_mode").is_some(),
+        );
+        let opc = format::OpcSettings {
+            ip: explicit_arg(matches, "ip")
+                .unwrap_or(&imported_opc.ip)
+                .to_string(),
+            username: explicit_arg(matches, "username")
+                .unwrap_or(&imported_opc.username)
+                .to_string(),
+            password: explicit_arg(matches, "password")
+                .unwrap_or(&imported_opc.password)
+                .to_string(),
+            application_name: explicit_arg(matches, "app_name")
+                .cloned()
+                .or(imported_opc.application_name),
+            product_uri: explicit_arg(matches, "product_uri")
+                .cloned()
+                .or(imported_opc.product_uri),
+            auth: match matches.value_source("opc_auth") {
+                Some(ValueSource::CommandLine) => auth_method(matches),
+                _ => imported_opc.auth,
+            },
+            client_certificate: imported_opc.client_certificate,
+            security_policy,
+            security_mode,
+            connect_timeout: explicit_arg(matches, "connect_timeout")
+                .unwrap_or(&imported_opc.connect_timeout)
+                .to_string(),
+            request_timeout: explicit_arg(matches, "request_timeout")
+                .unwrap_or(&imported_opc.request_timeout)
+                .to_string(),
+            session_timeout: explicit_arg(matches, "session_timeout")
+                .unwrap_or(&imported_opc.session_timeout)
+                .to_string(),
+            subscription_interval: explicit_arg(matches, "publishing_interval")
+                .cloned()
+                .or(imported_opc.subscription_interval),
+        };
+        check_credentials(matches, &opc);
+        check_endpoint(matches, &opc);
+        let outputs = influx_outputs(matches, &imported.outputs);
+        let agent = agent_settings(matches, Some(imported.agent));
+        let global_tags = global_tags(matches, imported.global_tags);
+        (imported.groups, agent, outputs, opc, global_tags)
+    } else {
+        let xml_files = find_xml_files(folder);
+
+        // Quiet runs can't ask, there --yes stands in for the answer, --force-send doesn't ask at all
+        if !matches.get_flag("force_send") && !matches.get_flag("yes") {
+            ask("whether to use these files", "add --yes or --force-send");
+        }
+        if matches.get_flag("force_send") {
+            say!("Using these files (--force-send).");
+        } else if output::is_quiet() || output::is_no_input() {
+            if !matches.get_flag("yes") {
+                eprintln!(
+                    "Error: {} can't ask whether to use these files, add --yes.",
+                    silencing_flag()
+                );
+                wrap_up(1);
+            }
+        } else {
+            println!();
+            println!("Do you want to use these files? (y/N)");
+            let mut confirm = String::new();
+            std::io::stdin().read_line(&mut confirm).unwrap();
+
+            if confirm.trim().to_lowercase() != "y" {
+                println!("Aborting.");
+                wrap_up(1);
+            }
+        }
+
+        // --listeners answers the listener question up front and must be right, otherwise ask until it is
+        let listener_indices = match matches.get_one::<String>("listeners") {
+            Some(listeners) => match parse_listener_selection(listeners, xml_files.len()) {
+                Ok(indices) => indices,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    wrap_up(1);
+                }
+            },
+            None => {
+                ask(
+                    "the files that should be listeners",
+                    "pass --listeners all, none or their indexes",
+                );
+                say!("OPC clients can be active (standard), pulling data every interval, or \npassive (subscribers), listening for changes.");
+                //println!();
+                loop {
+                    say!("Enter the indexes of the files that should be listeners (subscribers), \nseparated by commas (e.g., 1,3). If none, just press enter:");
+                    let mut listener_numbers = String::new();
+                    std::io::stdin().read_line(&mut listener_numbers).unwrap();
+                    match parse_listener_selection(&listener_numbers, xml_files.len()) {
+                        Ok(indices) => break indices,
+                        Err(e) => println!("Warning: {}, please try again.", e),
+                    }
+                }
+            }
+        };
+        let listener_files: Vec<String> = listener_indices
+            .iter()
+            .map(|&index| xml_files[index].clone())
+            .collect();
+
+        // token.txt is only needed when no --influx-token was given and InfluxDB is written to
+        let token = if prometheus_url(matches).is_some()
+            || !explicit_values(matches, "token_file").is_empty()
+        {
+            String::new()
+        } else if explicit_values(matches, "influx_token").is_empty() {
+            read_influx_token(token_folder)
+        } else {
+            String::new()
+        };
+        let outputs = influx_outputs(
+            matches,
+            &[format::InfluxOutput {
+                url: matches.get_one::<String>("influx_url").unwrap().to_string(),
+                token,
+                organization: matches.get_one::<String>("influx_org").unwrap().to_string(),
+                bucket: matches
+                    .get_one::<String>("influx_bucket")
+                    .unwrap()
+                    .to_string(),
+                bucket_tag: false,
+            }],
+        );
+
+        let (security_policy, security_mode) = security_settings(
+            matches.get_one::<String>("security_policy").unwrap(),
+            matches.get_one::<String>("security_mode").unwrap(),
+            explicit_arg(matches, "security_mode").is_some(),
+        );
+        let auth = auth_method(matches);
+        // Only username authentication puts the OPC password into the config
+        let password = match auth {
+            format::AuthMethod::UserName => read_password(matches, "password", "OPC password"),
+            _ => matches.get_one::<String>("password").unwrap().to_string(),
+        };
+        let opc = format::OpcSettings {
+            ip: ip.to_string(),
+            username: username.to_string(),
+            password,
+            application_name: matches.get_one::<String>("app_name").cloned(),
+            product_uri: matches.get_one::<String>("product_uri").cloned(),
+            auth,
+            client_certificate: None,
+            security_policy,
+            security_mode,
+            connect_timeout: matches
+                .get_one::<String>("connect_timeout")
+                .unwrap()
+                .to_string(),
+            request_timeout: matches
+                .get_one::<String>("request_timeout")
+                .unwrap()
+                .to_string(),
+            session_timeout: matches
+                .get_one::<String>("session_timeout")
+                .unwrap()
+                .to_string(),
+            subscription_interval: matches.get_one::<String>("publishing_interval").cloned(),
+        };
+        check_credentials(matches, &opc);
+        check_endpoint(matches, &opc);
+
+        let mut groups = Vec::new();
+        let mut processed = 0;
+        let mut failures: Vec<summary::FailedFile> = Vec::new();
+        // Parse a group from each XML file, checking whether it's a listener
+        let mut shared_answers = format::SharedAnswers::default();
+        if let Some(config_file) = matches.get_one::<String>("config") {
+            match profile::file_answers(config_file) {
+                Ok(file_answers) => {
+                    for file_answer in file_answers {
+                        shared_answers.preset(
+                            &file_answer.file_name,
+                            &file_answer.setting,
+                            &file_answer.answer,
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Error: unable to read the config file {}: {}",
+                        config_file, e
+                    );
+                    wrap_up(1);
+                }
+            }
+        }
+        // --file-bucket wins over a bucket from the config file
+        for (file_name, bucket) in matches
+            .get_many::<(String, String)>("file_bucket")
+            .into_iter()
+            .flatten()
+        {
+            let known = xml_files.iter().any(|file| {
+                Path::new(file).file_name().and_then(|name| name.to_str()) == Some(file_name)
+            });
+            if !known {
+                eprintln!(
+                    "Error: --file-bucket names {}, which is not one of the XML files found.",
+                    file_name
+                );
+                wrap_up(1);
+            }
+            shared_answers.preset(file_name, "bucket", bucket);
+        }
+        if let Some(namespace) = matches.get_one::<String>("namespace") {
+            shared_answers.answer_all("namespace number", namespace);
+        }
+        let node_filter = format::NodeFilter {
+            include: matches.get_one::<regex::Regex>("include").cloned(),
+            exclude: matches.get_one::<regex::Regex>("exclude").cloned(),
+        };
+        for (index, file) in xml_files.iter().enumerate() {
+            let is_listener = listener_files.contains(file);
+            let mut answers = Vec::new();
+            let parsed = format::parse_xml(
+                file,
+                is_listener,
+                matches.get_flag("all_namespaces"),
+                matches.get_flag("browse_paths"),
+                &node_filter,
+                &xml_files[index + 1..],
+                &mut shared_answers,
+                &mut answers,
+            );
+            say!(
+                "[{}/{}] Parsing {} ... {}",
+                index + 1,
+                xml_files.len(),
+                file,
+                file_status(&parsed, is_listener, matches.get_count("verbose") > 0)
+            );
+            match parsed {
+                // Telegraf rejects an empty nodes list, usually the namespace entered doesn't match the file
+                Ok(file_groups) if file_groups.iter().all(|group| group.nodes.is_empty()) => {
+                    let group = &file_groups[0];
+                    if matches.get_flag("strict") {
+                        eprintln!(
+                            "Error: no variables found in {}, check the namespace number entered for it ({}).",
+                            file, group.namespace
+                        );
+                        wrap_up(1);
+                    }
+                    ereport!(
+                        "Warning: no variables found in {}, leaving it out. Check the namespace number entered for it ({}).",
+                        file, group.namespace
+                    );
+                    failures.push(summary::FailedFile {
+                        file: file.clone(),
+                        error: format!(
+                            "no variables found, check the namespace number entered for it ({})",
+                            group.namespace
+                        ),
+                        answers,
+                    });
+                }
+                Ok(file_groups) => {
+                    processed += 1;
+                    groups.extend(
+                        file_groups
+                            .into_iter()
+                            .filter(|group| !group.nodes.is_empty()),
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    failures.push(summary::FailedFile {
+                        file: file.clone(),
+                        error: e.to_string(),
+                        answers,
+                    });
+                }
+            }
+        }
+
+        // Carry on with the files that parsed, but keep a record of the ones left out
+        let error_log = config_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(ERROR_LOG_NAME);
+        let writes_files = !matches.get_flag("dry_run") && !matches.get_flag("validate_only");
+        if failures.is_empty() {
+            // A log left over from an earlier run would describe files that are fine now
+            if writes_files && error_log.exists() {
+                if let Err(e) = fs::remove_file(&error_log) {
+                    ereport!("Warning: unable to remove {}: {}", error_log.display(), e);
+                }
+            }
+            say!("\n{} of {} files processed.", processed, xml_files.len());
+        } else {
+            let log_note = if !writes_files {
+                "no log written, this run doesn't write files".to_string()
+            } else {
+                match summary::write_error_log(&error_log, &failures) {
+                    Ok(()) => format!("see {}", error_log.display()),
+                    Err(e) => format!("unable to write {}: {}", error_log.display(), e),
+                }
+            };
+            eprintln!(
+                "\n{} of {} files processed, {} error(s) ({}):",
+                processed,
+                xml_files.len(),
+                failures.len(),
+                log_note
+            );
+            for failure in &failures {
+                eprintln!("  {}", failure.file);
+            }
+        }
+        xml_file_count = xml_files.len();
+        skipped_files = failures.into_iter().map(|failure| failure.file).collect();
+        if groups.is_empty() {
+            eprintln!("No groups left to generate a config from, aborting.");
+            wrap_up(1);
+        }
+        (
+            groups,
+            agent_settings(matches, None),
+            outputs,
+            opc,
+            global_tags(matches, Vec::new()),
+        )
+    };
+
+    // The generated pair is uploaded next to the config on the box
+    if client_certificate.is_some() {
+        let paths = (
+            REMOTE_CERTIFICATE_PATH.to_string(),
+            REMOTE_PRIVATE_KEY_PATH.to_string(),
+        );
+        match &mut opc.auth {
+            format::AuthMethod::Certificate {
+                certificate,
+                private_key,
+            } => (*certificate, *private_key) = paths,
+            _ => opc.client_certificate = Some(paths),
+        }
+    }
+
+    // The [fields] of a --config file give the same signal of different machines one field name
+    if let Some(config_file) = matches.get_one::<String>("config") {
+        match profile::field_names(config_file) {
+            Ok(fields) if !fields.is_empty() => {
+                let renamed = format::rename_fields(&mut groups, &fields);
+                say!(
+                    "Renamed {} node(s) after the [fields] of {}",
+                    renamed,
+                    config_file
+                );
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!(
+                    "Error: unable to read the config file {}: {}",
+                    config_file, e
+                );
+                wrap_up(1);
+            }
+        }
+    }
+
+    // Group names end up in queries, so keep them to plain characters
+    let auto_rename = matches.get_flag("auto_rename");
+    let duplicate_names = format::sanitize_group_names(&mut groups, auto_rename);
+    if !duplicate_names.is_empty() {
+        // Telegraf mixes the series of groups that share a name
+        println!(
+            "Warning: {} group(s) share a name with an earlier group:",
+            duplicate_names.len()
+        );
+        for duplicate in &duplicate_names {
+            println!(
+                "  '{}' from {} is also used by {}",
+                duplicate.name,
+                groups[duplicate.group].source,
+                groups[duplicate.first_group].source
+            );
+        }
+        if auto_rename {
+            for duplicate in &duplicate_names {
+                say!(
+                    "Renamed the group from {} to '{}'.",
+                    groups[duplicate.group].source,
+                    groups[duplicate.group].name
+                );
+            }
+        } else {
+            say!("Use --auto-rename to give them unique names with a numeric suffix.");
+        }
+    }
+
+    // --deadband covers the numeric listener nodes that don't bring a deadband of their own
+    if let Some(value) = matches.get_one::<f64>("deadband") {
+        if value.is_nan() || *value < 0.0 {
+            eprintln!(
+                "Error: Invalid deadband '{}', expecting a positive number",
+                value
+            );
+            wrap_up(1);
+        }
+        let deadband = format::Deadband {
+            kind: format::parse_deadband_type(matches.get_one::<String>("deadband_type").unwrap())
+                .unwrap()
+                .to_string(),
+            value: *value,
+        };
+        for group in groups.iter_mut().filter(|group| group.is_listener) {
+            for node in group.nodes.iter_mut() {
+                if node.deadband.is_none() && node.is_numeric() {
+                    node.deadband = Some(deadband.clone());
+                }
+            }
+        }
+    }
+
+    // Imported groups keep their own timestamp source unless --timestamp is given
+    if matches.get_one::<String>("import").is_none() || explicit_arg(matches, "timestamp").is_some()
+    {
+        let timestamp = matches.get_one::<String>("timestamp").unwrap();
+        for group in &mut groups {
+            group.timestamp = timestamp.clone();
+        }
+    }
+
+    // Give each group its own measurement instead of the shared plugin name
+    if matches.get_flag("measurement_per_group") {
+        format::assign_measurements(&mut groups);
+    }
+
+    // Overlapping exports would write the same series from several groups
+    let dedupe = matches.get_flag("dedupe");
+    let duplicates = format::find_duplicate_nodes(&mut groups, dedupe);
+    if !duplicates.is_empty() {
+        ereport!(
+            "Warning: {} node(s) appear in more than one group:",
+            duplicates.len()
+        );
+        for duplicate in &duplicates {
+            ereport!(
+                "  ns={};{}={} in group {} '{}', first seen in group {} '{}'",
+                duplicate.namespace,
+                duplicate.identifier_type,
+                duplicate.identifier,
+                duplicate.group + 1,
+                groups[duplicate.group].name,
+                duplicate.first_group + 1,
+                groups[duplicate.first_group].name
+            );
+        }
+        if dedupe {
+            say!("Dropped the later occurrences.");
+            // A group made only of duplicates has nothing left to read
+            groups.retain(|group| {
+                if group.nodes.is_empty() {
+                    say!(
+                        "Dropped group '{}', all of its nodes were duplicates.",
+                        group.name
+                    );
+                }
+                !group.nodes.is_empty()
+            });
+        } else {
+            say!("Use --dedupe to keep only the first occurrence of each node.");
+        }
+    }
+
+    // Groups with a bucket of their own are routed there by their bucket tag
+    if groups.iter().any(|group| group.bucket.is_some()) {
+        for output in &mut outputs {
+            output.bucket_tag = true;
+        }
+    }
+
+    // A rough figure to size the InfluxDB by, listeners only count as often as they may report
+    let node_count: usize = groups.iter().map(|group| group.nodes.len()).sum();
+    say!(
+        "Estimated write rate: about {:.1} points per second from {} nodes{}.",
+        summary::points_per_second(&groups),
+        node_count,
+        if groups.iter().any(|group| group.is_listener) {
+            ", at most for listeners since they only report changes"
+        } else {
+            ""
+        }
+    );
+
+    let config_strings = format::format_inputs(&opc, &groups);
+
+    // Combine all configuration strings into the final config file content, or splice them into the existing one
+    let merge = matches.get_flag("merge") && config_path.exists();
+    if (merge || append) && outputs.iter().any(|output| output.bucket_tag) {
+        // Only the inputs are replaced on --merge or added on --append-inputs, the outputs stay as they were
+        ereport!(
+            "Warning: the outputs in {} are kept as they are, add bucket_tag = \"{}\" to them for the per-file buckets.",
+            config_path.display(),
+            format::BUCKET_TAG
+        );
+    }
+    let config_content = if append {
+        let appended = fs::read_to_string(&config_path)
+            .map_err(|e| e.into())
+            .and_then(|existing| merge::append_inputs(&existing, &config_strings));
+        match appended {
+            Ok(appended) => {
+                say!(
+                    "Appending {} input(s) to {}, the rest of it is kept.",
+                    config_strings.len(),
+                    config_path.display()
+                );
+                appended
+            }
+            Err(e) => {
+                eprintln!("Failed to append to {}: {}", config_path.display(), e);
+                wrap_up(1);
+            }
+        }
+    } else if merge {
+        let merged = fs::read_to_string(&config_path)
+            .map_err(|e| e.into())
+            .and_then(|existing| {
+                merge::merge_config(&existing, &format::generated_section(&config_strings))
+            });
+        match merged {
+            Ok(merged) => {
+                say!(
+                    "Merging the generated inputs into {}, the rest of it is kept.",
+                    config_path.display()
+                );
+                merged
+            }
+            Err(e) => {
+                eprintln!("Failed to merge into {}: {}", config_path.display(), e);
+                wrap_up(1);
+            }
+        }
+    } else {
+        let template = match matches.get_one::<String>("template") {
+            Some(template_path) => match fs::read_to_string(template_path) {
+                Ok(template) => template,
+                Err(e) => {
+                    eprintln!("Error: unable to read template {}: {}", template_path, e);
+                    wrap_up(1);
+                }
+            },
+            None => format::DEFAULT_TEMPLATE.to_string(),
+        };
+        match format::generate_config_content(
+            &template,
+            &global_tags,
+            &agent,
+            &outputs,
+            prometheus_url(matches),
+            &config_strings,
+        ) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                wrap_up(1);
+            }
+        }
+    };
+
+    // Lint the config in memory and stop, nothing is written or sent
+    if matches.get_flag("validate_only") {
+        match format::validate_config(&config_content) {
+            Ok(()) => {
+                println!("The generated config is valid TOML.");
+                wrap_up(0);
+            }
+            Err(e) => {
+                eprintln!("Error: the generated config is not valid TOML, {}", e);
+                wrap_up(1);
+            }
+        }
+    }
+
+    // Show what would be written instead of touching any files
+    if matches.get_flag("dry_run") {
+        say!("Dry run, nothing is written or sent. Nodes per group:");
+        for group in &groups {
+            say!(
+                "  {} ({}): {} nodes",
+                group.name,
+                if group.is_listener {
+                    "listener"
+                } else {
+                    "standard"
+                },
+                group.nodes.len()
+            );
+        }
+        say!();
+        println!("{}", config_content);
+        print_summary(
+            matches,
+            &summary::Summary::new(xml_file_count, skipped_files, &groups, None),
         );
         wrap_up(0);
     }
+
+    // Don't replace an existing config without asking, merging into it was asked for already
+    if config_path.exists()
+        && !merge
+        && !append
+        && !matches.get_flag("yes")
+        && !matches.get_flag("force_send")
+    {
+        ask(
+            &format!("whether to overwrite {}", config_path.display()),
+            "add --yes, or --merge to update it",
+        );
+        if output::is_quiet() {
+            eprintln!(
+                "Error: {} already exists, --quiet only overwrites it with --yes.",
+                config_path.display()
+            );
+            wrap_up(1);
+        }
+        println!(
+            "{} already exists, do you want to overwrite it? (y/N)",
+            config_path.display()
+        );
+        let mut overwrite = String::new();
+        std::io::stdin().read_line(&mut overwrite).unwrap();
+        if !overwrite.trim().eq_ignore_ascii_case("y") {
+            println!("Aborting.");
+            wrap_up(1);
+        }
+    }
+
+    // Write the config file, creating the output directory if needed
+    if let Some(parent) = config_path.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            eprintln!("Failed to create {}: {}", parent.display(), e);
+            wrap_up(1);
+        }
+    }
+    if let Err(e) = write_atomically(&config_path, &config_content) {
+        eprintln!("Failed to write {}: {}", config_path.display(), e);
+        wrap_up(1);
+    }
+    log::debug!(
+        "Wrote {} bytes to {}",
+        config_content.len(),
+        config_path.display()
+    );
+
+    println!(
+        "Config file generated successfully at {}!",
+        config_path.display()
+    );
+
+    let mut summary =
+        summary::Summary::new(xml_file_count, skipped_files, &groups, Some(&config_path));
+
+    // Ask the user if they want to automatically send the generated config file to the IOT box,
+    // quiet and --no-input runs leave that to the send subcommand unless --force-send is given
+    let mut user_input = String::new();
+    if matches.get_flag("force_send") {
+        user_input.push('y');
+    } else if !output::is_quiet() && !output::is_no_input() {
+        println!("Do you want to send the config file to the IOT box? (y/N)");
+        std::io::stdin().read_line(&mut user_input).unwrap();
+    }
+    if user_input.trim().eq_ignore_ascii_case("y") {
+        summary.sent = match &client_certificate {
+            Some(files) => send_client_certificate(matches, files),
+            None => true,
+        } && send_to_hosts(matches, &config_path);
+        print_summary(matches, &summary);
+        // Only a failed host makes the run fail, scripts rely on the exit code
+        wrap_up(if summary.sent { 0 } else { 1 });
+    } else {
+        say!("Config file generated. Please copy it and run telegraf manually.");
+        print_summary(matches, &summary);
+        wrap_up(0);
+    }
+}
+
+fn main() {
+    // Main function: Parses the subcommand and hands off to it, running without one generates a config
+    // The command line wins over a --config file, and both win over a --profile
+    let args = profile::apply_config_file(cli::build_cli(), cli::args());
+    let args = profile::apply_profile(cli::build_cli(), args);
+    let cli_matches = cli::build_cli().get_matches_from(args);
+    let (name, matches) = cli_matches.subcommand().unwrap();
+    // The script goes to stdout on its own, without the config printout or a profile
+    if name == "completions" {
+        let shell = *matches.get_one::<clap_complete::Shell>("shell").unwrap();
+        clap_complete::generate(shell, &mut cli::build_cli(), bin_name(), &mut io::stdout());
+        wrap_up(0);
+    }
+    // Nested commands like backup influx are named by both levels
+    let (command, matches) = match matches.subcommand() {
+        Some((action, action_matches)) => (format!("{} {}", name, action), action_matches),
+        None => (name.to_string(), matches),
+    };
+
+    // print the current config
+    output::set_quiet(matches.get_flag("quiet"));
+    output::set_no_input(matches.get_flag("no_input"));
+    // --list-nodes output is meant to be redirected, e.g. into a CSV file
+    output::set_chatter_to_stderr(command == "generate" && matches.get_flag("list_nodes"));
+    output::init_logging(matches.get_count("verbose"));
+    log::info!("Running {}", command);
+    if !output::is_quiet() {
+        print_config(&command, matches);
+    }
+
+    if let Err(e) = profile::save_profile(&cli::build_cli(), &cli_matches) {
+        eprintln!("Error: unable to save the profile: {}", e);
+        wrap_up(1);
+    }
+
+    // Check if IOT host IP address is valid, diff doesn't connect to it
+    for iot_host in iot_hosts(matches).into_iter().filter(|_| command != "diff") {
+        if format::split_host_port(iot_host).is_none() {
+            eprintln!(
+                "Error: Invalid IOT host format for '{}', expecting something like: 192.168.0.1:22, iot2050.local:22 or [fe80::1]:22",
+                iot_host
+            );
+            wrap_up(1);
+        }
+    }
+
+    // A dry run never talks to the IOT box
+    if command != "generate" && matches.get_flag("dry_run") {
+        say!("Dry run: skipping all operations on the IOT box.");
+        wrap_up(0);
+    }
+
+    match command.as_str() {
+        "generate" => generate(matches),
+        "send" => send_config(matches),
+        "send-file" => send_file(matches),
+        "pull" => pull_config(matches),
+        "diff" => diff_configs(matches),
+        "backup influx" => backup_influx(matches),
+        "backup grafana" => backup_grafana(matches),
+        "restore influx" => restore_influx(matches),
+        _ => unreachable!("clap only accepts the subcommands defined in cli.rs"),
+    }
 }
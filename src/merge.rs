@@ -0,0 +1,193 @@
+use crate::format::{generated_section, BEGIN_MARKER, END_MARKER};
+use toml::{Table, Value};
+
+fn opc_input_count(config: &Table) -> usize {
+    // Counts [[inputs.opcua]] and [[inputs.opcua_listener]] blocks
+    let inputs = match config.get("inputs").and_then(Value::as_table) {
+        Some(inputs) => inputs,
+        None => return 0,
+    };
+    ["opcua", "opcua_listener"]
+        .iter()
+        .filter_map(|plugin| inputs.get(*plugin).and_then(Value::as_array))
+        .map(Vec::len)
+        .sum()
+}
+
+fn group_names(config: &Table) -> Vec<String> {
+    // The names of the groups of every OPC UA input
+    let Some(inputs) = config.get("inputs").and_then(Value::as_table) else {
+        return Vec::new();
+    };
+    ["opcua", "opcua_listener"]
+        .iter()
+        .filter_map(|plugin| inputs.get(*plugin).and_then(Value::as_array))
+        .flatten()
+        .filter_map(|input| input.get("group").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|group| group.get("name").and_then(Value::as_str))
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn append_inputs(
+    existing: &str,
+    inputs: &[String],
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Adds inputs at the end of the generated section of an existing config and keeps everything it holds
+    let config = toml::from_str::<Table>(existing)
+        .map_err(|e| format!("the existing config is not valid TOML: {}", e))?;
+
+    // A group that is already there would have its nodes read twice
+    let existing_names = group_names(&config);
+    for name in group_names(&toml::from_str::<Table>(&inputs.join("\n\n"))?) {
+        if existing_names.contains(&name) {
+            println!(
+                "Warning: the existing config already has a group '{}', its nodes may be read twice.",
+                name
+            );
+        }
+    }
+
+    let lines: Vec<&str> = existing.lines().collect();
+    let end = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with(BEGIN_MARKER))
+        .map(|begin| {
+            lines[begin..]
+                .iter()
+                .position(|line| line.trim_start().starts_with(END_MARKER))
+                .map(|end| begin + end)
+                .ok_or("the existing config has a begin marker but no end marker")
+        })
+        .transpose()?;
+    let mut appended = match end {
+        Some(end) => format!(
+            "{}\n{}\n{}",
+            lines[..end].join("\n"),
+            inputs.join("\n\n"),
+            lines[end..].join("\n")
+        ),
+        // Configs from before the markers get a generated section of their own
+        None => format!("{}\n\n{}", existing.trim_end(), generated_section(inputs)),
+    };
+    if !appended.ends_with('\n') {
+        appended.push('\n');
+    }
+
+    toml::from_str::<Table>(&appended).map_err(|e| {
+        format!(
+            "the config with the appended inputs is not valid TOML: {}",
+            e
+        )
+    })?;
+    Ok(appended)
+}
+
+pub fn merge_config(existing: &str, section: &str) -> Result<String, Box<dyn std::error::Error>> {
+    // Swaps the generated section of an existing config for a new one and keeps everything else as written
+    toml::from_str::<Table>(existing)
+        .map_err(|e| format!("the existing config is not valid TOML: {}", e))?;
+
+    let lines: Vec<&str> = existing.lines().collect();
+    let begin = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with(BEGIN_MARKER));
+    let (before, after) = match begin {
+        Some(begin) => {
+            let end = lines[begin..]
+                .iter()
+                .position(|line| line.trim_start().starts_with(END_MARKER))
+                .map(|end| begin + end)
+                .ok_or("the existing config has a begin marker but no end marker")?;
+            (lines[..begin].join("\n"), lines[end + 1..].join("\n"))
+        }
+        // Configs from before the markers get the section appended, their inputs are left alone
+        None => (existing.trim_end().to_string() + "\n", String::new()),
+    };
+
+    // Hand-written OPC UA inputs outside the markers would read the same nodes twice
+    let kept = opc_input_count(&toml::from_str::<Table>(&format!("{}\n{}", before, after))?);
+    if kept > 0 {
+        println!(
+            "Warning: the existing config keeps {} OPC UA input(s) outside the generated section.",
+            kept
+        );
+    }
+
+    let mut merged = format!("{}\n{}\n{}", before, section, after);
+    if !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+
+    // Only hand back something Telegraf will be able to parse
+    toml::from_str::<Table>(&merged)
+        .map_err(|e| format!("the merged config is not valid TOML: {}", e))?;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "[[inputs.opcua]]\nname = \"opcua\"\nendpoint = \"opc.tcp://plc:4840\"";
+
+    fn section(inputs: &[&str]) -> String {
+        let inputs: Vec<String> = inputs.iter().map(|input| input.to_string()).collect();
+        generated_section(&inputs)
+    }
+
+    fn config(generated: &str) -> String {
+        format!(
+            "# kept\n[agent]\ninterval = \"5s\"\n\n{}\n\n[[outputs.file]]\nfiles = [\"stdout\"]\n",
+            generated
+        )
+    }
+
+    #[test]
+    fn merge_replaces_only_the_generated_section() {
+        let existing = config(&format!("{}\n{}", BEGIN_MARKER, END_MARKER));
+        let merged = merge_config(&existing, &section(&[INPUT])).unwrap();
+        assert!(merged.starts_with("# kept\n[agent]\ninterval = \"5s\"\n"));
+        assert!(merged.ends_with("[[outputs.file]]\nfiles = [\"stdout\"]\n"));
+        assert_eq!(merged.matches(BEGIN_MARKER).count(), 1);
+        assert_eq!(merged.matches(END_MARKER).count(), 1);
+        assert!(merged.contains("endpoint = \"opc.tcp://plc:4840\""));
+    }
+
+    #[test]
+    fn merge_twice_gives_the_same_config() {
+        let existing = config(&section(&[INPUT]));
+        let new_section = section(&[INPUT]);
+        let once = merge_config(&existing, &new_section).unwrap();
+        assert_eq!(merge_config(&once, &new_section).unwrap(), once);
+        assert_eq!(opc_input_count(&toml::from_str(&once).unwrap()), 1);
+    }
+
+    #[test]
+    fn merge_appends_to_configs_without_markers() {
+        let merged = merge_config("[agent]\ninterval = \"5s\"\n", &section(&[INPUT])).unwrap();
+        assert!(merged.starts_with("[agent]\ninterval = \"5s\"\n\n"));
+        assert!(merged.contains(BEGIN_MARKER));
+        assert!(merged.trim_end().ends_with(END_MARKER));
+    }
+
+    #[test]
+    fn merge_rejects_broken_configs() {
+        let unterminated = config(BEGIN_MARKER);
+        assert!(merge_config(&unterminated, &section(&[INPUT])).is_err());
+        assert!(merge_config("[agent\n", &section(&[INPUT])).is_err());
+        let existing = config(&section(&[]));
+        assert!(merge_config(&existing, &section(&["[[inputs.opcua"])).is_err());
+    }
+
+    #[test]
+    fn append_adds_inputs_before_the_end_marker() {
+        let existing = config(&section(&[INPUT]));
+        let listener = "[[inputs.opcua_listener]]\nname = \"opcua_listener\"";
+        let appended = append_inputs(&existing, &[listener.to_string()]).unwrap();
+        let end = appended.find(END_MARKER).unwrap();
+        assert!(appended.find(listener).unwrap() < end);
+        assert_eq!(opc_input_count(&toml::from_str(&appended).unwrap()), 2);
+    }
+}
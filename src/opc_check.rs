@@ -0,0 +1,569 @@
+use crate::format::{AuthMethod, OpcSettings};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long to wait for the OPC UA server to connect and answer each message.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+const POLICY_PREFIX: &str = "http://opcfoundation.org/UA/SecurityPolicy#";
+
+/// An endpoint the server offers, as far as the check cares about it.
+struct Endpoint {
+    security_policy: String,
+    security_mode: String,
+    /// `Anonymous`, `UserName`, `Certificate` or `IssuedToken` for each user token type the endpoint accepts.
+    token_types: Vec<String>,
+}
+
+/// Builds binary-encoded OPC UA messages.
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    fn i64(&mut self, value: i64) -> &mut Self {
+        self.bytes.extend(value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: Option<&str>) -> &mut Self {
+        // Strings and ByteStrings are length-prefixed, -1 standing for null
+        match value {
+            Some(value) => {
+                self.i32(value.len() as i32);
+                self.bytes.extend(value.as_bytes());
+            }
+            None => {
+                self.i32(-1);
+            }
+        }
+        self
+    }
+
+    fn type_id(&mut self, id: u16) -> &mut Self {
+        // Four-byte NodeId in namespace 0, the form every service type id fits into
+        self.u8(0x01).u8(0).u16(id)
+    }
+
+    fn request_header(&mut self, handle: u32) -> &mut Self {
+        // No session yet, so a null authentication token and no additional header
+        self.u8(0x00).u8(0x00);
+        self.i64(0).u32(handle).u32(0).string(None).u32(10000);
+        self.u8(0x00).u8(0x00).u8(0x00)
+    }
+}
+
+/// Reads binary-encoded OPC UA messages, failing on anything cut short.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if self.bytes.len() < count {
+            return Err("the OPC UA server sent a truncated message".into());
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn std::error::Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Box<dyn std::error::Error>> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, Box<dyn std::error::Error>> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let length = self.i32()?;
+        if length < 0 {
+            return Ok(String::new());
+        }
+        Ok(String::from_utf8_lossy(self.take(length as usize)?).into_owned())
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let count = self.i32()?.max(0);
+        (0..count).map(|_| self.string()).collect()
+    }
+
+    fn node_id(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        // Returns numeric ids, which is all type ids are, and skips over the other kinds
+        let encoding = self.u8()?;
+        let id = match encoding & 0x0f {
+            0x00 => self.u8()? as u32,
+            0x01 => {
+                self.u8()?;
+                self.u16()? as u32
+            }
+            0x02 => {
+                self.u16()?;
+                self.u32()?
+            }
+            0x03 | 0x05 => {
+                self.u16()?;
+                self.string()?;
+                0
+            }
+            0x04 => {
+                self.u16()?;
+                self.take(16)?;
+                0
+            }
+            _ => return Err("the OPC UA server sent an unknown NodeId encoding".into()),
+        };
+        if encoding & 0x80 != 0 {
+            self.string()?;
+        }
+        if encoding & 0x40 != 0 {
+            self.u32()?;
+        }
+        Ok(id)
+    }
+
+    fn diagnostic_info(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mask = self.u8()?;
+        for bit in [0x01, 0x02, 0x04, 0x08] {
+            if mask & bit != 0 {
+                self.i32()?;
+            }
+        }
+        if mask & 0x10 != 0 {
+            self.string()?;
+        }
+        if mask & 0x20 != 0 {
+            self.u32()?;
+        }
+        if mask & 0x40 != 0 {
+            self.diagnostic_info()?;
+        }
+        Ok(())
+    }
+
+    fn localized_text(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mask = self.u8()?;
+        if mask & 0x01 != 0 {
+            self.string()?;
+        }
+        if mask & 0x02 != 0 {
+            self.string()?;
+        }
+        Ok(())
+    }
+
+    fn response_header(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        // Returns the service result, 0 being Good
+        self.take(8)?;
+        self.u32()?;
+        let service_result = self.u32()?;
+        self.diagnostic_info()?;
+        self.strings()?;
+        self.node_id()?;
+        if self.u8()? != 0 {
+            self.string()?;
+        }
+        Ok(service_result)
+    }
+}
+
+fn send_message(
+    stream: &mut TcpStream,
+    kind: &[u8; 3],
+    body: &Encoder,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Every message starts with its kind, F for a final chunk and its total size
+    let mut message = Encoder::default();
+    message.bytes.extend(kind);
+    message.u8(b'F').u32(body.bytes.len() as u32 + 8);
+    message.bytes.extend(&body.bytes);
+    stream.write_all(&message.bytes)?;
+    Ok(())
+}
+
+fn receive_message(
+    stream: &mut TcpStream,
+) -> Result<([u8; 3], Vec<u8>), Box<dyn std::error::Error>> {
+    // Joins chunked answers into one body, error messages end the check with the server's reason
+    let mut body = Vec::new();
+    loop {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header)?;
+        let size = u32::from_le_bytes(header[4..8].try_into()?) as usize;
+        let mut chunk = vec![0u8; size.saturating_sub(8)];
+        stream.read_exact(&mut chunk)?;
+        let kind = [header[0], header[1], header[2]];
+        if &kind == b"ERR" {
+            let mut decoder = Decoder { bytes: &chunk };
+            let status = decoder.u32()?;
+            let reason = decoder.string()?;
+            return Err(
+                format!("the OPC UA server refused with 0x{:08X} {}", status, reason).into(),
+            );
+        }
+        // Secure messages repeat their channel and sequence headers in every chunk
+        let skip = if body.is_empty() || &kind == b"ACK" {
+            0
+        } else {
+            16
+        };
+        body.extend(&chunk[skip.min(chunk.len())..]);
+        match header[3] {
+            b'F' => return Ok((kind, body)),
+            b'C' => {}
+            _ => return Err("the OPC UA server aborted its answer".into()),
+        }
+    }
+}
+
+fn check_service_result(
+    service_result: u32,
+    service: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if service_result != 0 {
+        return Err(format!("{} failed with status 0x{:08X}", service, service_result).into());
+    }
+    Ok(())
+}
+
+fn get_endpoints(endpoint_url: &str) -> Result<Vec<Endpoint>, Box<dyn std::error::Error>> {
+    // Hello, an unsecured channel and GetEndpoints: enough to see what the server offers, no session needed
+    let address = endpoint_url
+        .strip_prefix("opc.tcp://")
+        .ok_or("the endpoint is not an opc.tcp:// URL")?;
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("unable to resolve {}", address))?;
+    let mut stream = TcpStream::connect_timeout(&address, CHECK_TIMEOUT)?;
+    stream.set_read_timeout(Some(CHECK_TIMEOUT))?;
+    stream.set_write_timeout(Some(CHECK_TIMEOUT))?;
+
+    let mut hello = Encoder::default();
+    hello.u32(0).u32(65536).u32(65536).u32(0).u32(0);
+    hello.string(Some(endpoint_url));
+    send_message(&mut stream, b"HEL", &hello)?;
+    let (kind, _) = receive_message(&mut stream)?;
+    if &kind != b"ACK" {
+        return Err("the server doesn't speak OPC UA".into());
+    }
+
+    let mut open = Encoder::default();
+    open.u32(0);
+    open.string(Some(&format!("{}None", POLICY_PREFIX)))
+        .string(None)
+        .string(None);
+    open.u32(1).u32(1);
+    open.type_id(446).request_header(1);
+    open.u32(0).i32(0).i32(1).string(Some("")).u32(600000);
+    send_message(&mut stream, b"OPN", &open)?;
+    let (_, body) = receive_message(&mut stream)?;
+    let mut decoder = Decoder { bytes: &body };
+    let channel_id = decoder.u32()?;
+    decoder.string()?;
+    decoder.string()?;
+    decoder.string()?;
+    decoder.take(8)?;
+    let type_id = decoder.node_id()?;
+    check_service_result(decoder.response_header()?, "OpenSecureChannel")?;
+    if type_id != 449 {
+        return Err("the OPC UA server didn't open a secure channel".into());
+    }
+    decoder.u32()?;
+    decoder.u32()?;
+    let token_id = decoder.u32()?;
+
+    let mut request = Encoder::default();
+    request.u32(channel_id).u32(token_id).u32(2).u32(2);
+    request.type_id(428).request_header(2);
+    request.string(Some(endpoint_url)).i32(0).i32(0);
+    send_message(&mut stream, b"MSG", &request)?;
+    let (_, body) = receive_message(&mut stream)?;
+    let mut decoder = Decoder { bytes: &body };
+    decoder.take(16)?;
+    let type_id = decoder.node_id()?;
+    check_service_result(decoder.response_header()?, "GetEndpoints")?;
+    if type_id != 431 {
+        return Err("the OPC UA server didn't answer GetEndpoints".into());
+    }
+
+    let mut endpoints = Vec::new();
+    for _ in 0..decoder.i32()?.max(0) {
+        decoder.string()?;
+        decoder.string()?;
+        decoder.string()?;
+        decoder.localized_text()?;
+        decoder.u32()?;
+        decoder.string()?;
+        decoder.string()?;
+        decoder.strings()?;
+        decoder.string()?;
+        let security_mode = match decoder.u32()? {
+            1 => "None",
+            2 => "Sign",
+            3 => "SignAndEncrypt",
+            _ => "Invalid",
+        };
+        let security_policy = decoder.string()?;
+        let mut token_types = Vec::new();
+        for _ in 0..decoder.i32()?.max(0) {
+            decoder.string()?;
+            let token_type = match decoder.u32()? {
+                0 => "Anonymous",
+                1 => "UserName",
+                2 => "Certificate",
+                _ => "IssuedToken",
+            };
+            token_types.push(token_type.to_string());
+            decoder.string()?;
+            decoder.string()?;
+            decoder.string()?;
+        }
+        decoder.string()?;
+        decoder.u8()?;
+        endpoints.push(Endpoint {
+            security_policy: security_policy
+                .strip_prefix(POLICY_PREFIX)
+                .unwrap_or(&security_policy)
+                .to_string(),
+            security_mode: security_mode.to_string(),
+            token_types,
+        });
+    }
+
+    // Say goodbye so the server frees the channel right away
+    let mut close = Encoder::default();
+    close.u32(channel_id).u32(token_id).u32(3).u32(3);
+    close.type_id(452).request_header(3);
+    let _ = send_message(&mut stream, b"CLO", &close);
+    Ok(endpoints)
+}
+
+/// Asks the OPC UA server for its endpoints and checks that one has the security policy, mode and
+/// user token type the config will ask for. No session is opened, so credentials aren't checked.
+pub fn check_endpoint(
+    opc: &OpcSettings,
+    endpoint_url: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let endpoints = get_endpoints(endpoint_url)?;
+    let token_type = match opc.auth {
+        AuthMethod::Anonymous => "Anonymous",
+        AuthMethod::UserName => "UserName",
+        AuthMethod::Certificate { .. } => "Certificate",
+    };
+    // Telegraf's "auto" takes whatever policy or mode the server offers
+    let matching = endpoints.iter().any(|endpoint| {
+        (opc.security_policy == "auto" || endpoint.security_policy == opc.security_policy)
+            && (opc.security_mode == "auto" || endpoint.security_mode == opc.security_mode)
+            && endpoint
+                .token_types
+                .iter()
+                .any(|offered| offered == token_type)
+    });
+    if matching {
+        return Ok(());
+    }
+    let offered: Vec<String> = endpoints
+        .iter()
+        .map(|endpoint| {
+            format!(
+                "{}/{} ({})",
+                endpoint.security_policy,
+                endpoint.security_mode,
+                endpoint.token_types.join(", ")
+            )
+        })
+        .collect();
+    Err(format!(
+        "no endpoint offers {}/{} with {} user tokens, the server offers: {}",
+        opc.security_policy,
+        opc.security_mode,
+        token_type,
+        if offered.is_empty() {
+            "nothing".to_string()
+        } else {
+            offered.join("; ")
+        }
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_little_endian() {
+        let mut encoder = Encoder::default();
+        encoder.u8(0x01).u16(0x0203).u32(0x04050607).i32(-2).i64(1);
+        assert_eq!(
+            encoder.bytes,
+            [
+                0x01, 0x03, 0x02, 0x07, 0x06, 0x05, 0x04, 0xfe, 0xff, 0xff, 0xff, 1, 0, 0, 0, 0, 0,
+                0, 0
+            ]
+        );
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        assert_eq!(decoder.u8().unwrap(), 0x01);
+        assert_eq!(decoder.u16().unwrap(), 0x0203);
+        assert_eq!(decoder.u32().unwrap(), 0x04050607);
+        assert_eq!(decoder.i32().unwrap(), -2);
+        assert_eq!(decoder.take(8).unwrap(), [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(decoder.bytes.is_empty());
+    }
+
+    #[test]
+    fn strings_are_length_prefixed() {
+        let mut encoder = Encoder::default();
+        encoder.string(Some("opc")).string(None).string(Some(""));
+        assert_eq!(
+            encoder.bytes,
+            [3, 0, 0, 0, b'o', b'p', b'c', 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]
+        );
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        assert_eq!(decoder.string().unwrap(), "opc");
+        // Null strings read as empty ones
+        assert_eq!(decoder.string().unwrap(), "");
+        assert_eq!(decoder.string().unwrap(), "");
+    }
+
+    #[test]
+    fn string_arrays_are_counted() {
+        let mut encoder = Encoder::default();
+        encoder.i32(2).string(Some("a")).string(Some("bc")).i32(-1);
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        assert_eq!(decoder.strings().unwrap(), ["a", "bc"]);
+        assert!(decoder.strings().unwrap().is_empty());
+    }
+
+    #[test]
+    fn type_ids_are_four_byte_node_ids() {
+        let mut encoder = Encoder::default();
+        encoder.type_id(446);
+        assert_eq!(encoder.bytes, [0x01, 0x00, 0xbe, 0x01]);
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        assert_eq!(decoder.node_id().unwrap(), 446);
+    }
+
+    #[test]
+    fn node_ids_of_every_encoding_are_read() {
+        let mut encoder = Encoder::default();
+        // Two-byte, numeric, string, GUID, then numeric with namespace URI and server index
+        encoder.u8(0x00).u8(85);
+        encoder.u8(0x02).u16(2).u32(70000);
+        encoder.u8(0x03).u16(3).string(Some("DB1.Speed"));
+        encoder.u8(0x04).u16(4);
+        encoder.bytes.extend([0xab; 16]);
+        encoder
+            .u8(0xc2)
+            .u16(0)
+            .u32(7)
+            .string(Some("urn:plc"))
+            .u32(1);
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        assert_eq!(decoder.node_id().unwrap(), 85);
+        assert_eq!(decoder.node_id().unwrap(), 70000);
+        assert_eq!(decoder.node_id().unwrap(), 0);
+        assert_eq!(decoder.node_id().unwrap(), 0);
+        assert_eq!(decoder.node_id().unwrap(), 7);
+        assert!(decoder.bytes.is_empty());
+
+        let mut decoder = Decoder { bytes: &[0x06] };
+        assert!(decoder.node_id().is_err());
+    }
+
+    #[test]
+    fn request_headers_have_a_fixed_size() {
+        let mut encoder = Encoder::default();
+        encoder.request_header(7);
+        // Null token (2), timestamp (8), handle (4), diagnostics (4), audit id (4), timeout (4), extension (3)
+        assert_eq!(encoder.bytes.len(), 29);
+        assert_eq!(encoder.bytes[10..14], 7u32.to_le_bytes());
+    }
+
+    #[test]
+    fn response_headers_give_the_service_result() {
+        let mut encoder = Encoder::default();
+        encoder.i64(0).u32(1).u32(0x80340000);
+        // Diagnostics with a symbolic id and nested inner diagnostics holding a text
+        encoder.u8(0x41).i32(1).u8(0x10).string(Some("inner"));
+        encoder.i32(1).string(Some("BadSessionIdInvalid"));
+        encoder.u8(0x00).u8(0x00);
+        encoder.u8(0x01).string(Some("extension"));
+        encoder.u32(42);
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        assert_eq!(decoder.response_header().unwrap(), 0x80340000);
+        assert_eq!(decoder.u32().unwrap(), 42);
+    }
+
+    #[test]
+    fn localized_texts_are_skipped() {
+        let mut encoder = Encoder::default();
+        encoder
+            .u8(0x03)
+            .string(Some("en"))
+            .string(Some("Server"))
+            .u8(0x00)
+            .u8(0xff);
+        let mut decoder = Decoder {
+            bytes: &encoder.bytes,
+        };
+        decoder.localized_text().unwrap();
+        decoder.localized_text().unwrap();
+        assert_eq!(decoder.u8().unwrap(), 0xff);
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        let mut decoder = Decoder { bytes: &[1, 0] };
+        assert!(decoder.u32().is_err());
+        let mut decoder = Decoder {
+            bytes: &[5, 0, 0, 0, b'a'],
+        };
+        assert!(decoder.string().is_err());
+        let mut decoder = Decoder { bytes: &[0x01, 0] };
+        assert!(decoder.node_id().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static NO_INPUT: AtomicBool = AtomicBool::new(false);
+static CHATTER_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Writes log records to stderr with a timestamp, apart from the prompts and results on stdout.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        // Straight to the terminal even while a host's output is held back, so times stay in order
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {:<5} {}: {}",
+                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Turns logging on for each -v: info, then debug, then trace. Without -v nothing is logged.
+pub fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Held while a host's buffered output is printed, so hosts don't interleave.
+static PRINTING: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    /// Lines held back while the thread works on one host, each marked whether it goes to stderr.
+    static CAPTURE: RefCell<Option<Vec<(bool, String)>>> = const { RefCell::new(None) };
+}
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Sends what say! prints to stderr, for runs whose stdout is data like a CSV.
+pub fn set_chatter_to_stderr(to_stderr: bool) {
+    CHATTER_TO_STDERR.store(to_stderr, Ordering::Relaxed);
+}
+
+pub fn chatter_to_stderr() -> bool {
+    CHATTER_TO_STDERR.load(Ordering::Relaxed)
+}
+
+pub fn set_no_input(no_input: bool) {
+    NO_INPUT.store(no_input, Ordering::Relaxed);
+}
+
+pub fn is_no_input() -> bool {
+    NO_INPUT.load(Ordering::Relaxed)
+}
+
+/// Fails instead of asking for `input` when --no-input is given, `instead` says where it can come from.
+pub fn ask(input: &str, instead: &str) -> Result<(), String> {
+    if is_no_input() {
+        Err(format!("--no-input can't ask for {}, {}", input, instead))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn is_capturing() -> bool {
+    CAPTURE.with(|capture| capture.borrow().is_some())
+}
+
+pub fn line(to_stderr: bool, text: String) {
+    // Goes into the thread's buffer while capturing, straight to the terminal otherwise
+    let text = CAPTURE.with(|capture| match capture.borrow_mut().as_mut() {
+        Some(lines) => {
+            lines.push((to_stderr, text));
+            None
+        }
+        None => Some(text),
+    });
+    if let Some(text) = text {
+        if to_stderr {
+            eprintln!("{}", text);
+        } else {
+            println!("{}", text);
+        }
+    }
+}
+
+pub fn captured<R>(run: impl FnOnce() -> R) -> (R, Vec<(bool, String)>) {
+    // Runs with every line of output held back, and hands the lines over afterwards
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(Vec::new()));
+    let result = run();
+    let lines = CAPTURE.with(|capture| capture.borrow_mut().take().unwrap_or_default());
+    (result, lines)
+}
+
+pub fn print_captured(lines: &[(bool, String)]) {
+    // Prints a whole buffer at once, other threads wait until it is done
+    let _printing = PRINTING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (to_stderr, text) in lines {
+        if *to_stderr {
+            eprintln!("{}", text);
+        } else {
+            println!("{}", text);
+        }
+    }
+}
+
+/// Like println!, for the progress and chatter that --quiet leaves out.
+macro_rules! say {
+    () => {
+        say!("")
+    };
+    ($($arg:tt)*) => {
+        if !$crate::output::is_quiet() {
+            $crate::output::line($crate::output::chatter_to_stderr(), format!($($arg)*));
+        }
+    };
+}
+
+/// Like println!, for results that stay in the host's own block when hosts run in parallel.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        $crate::output::line(false, format!($($arg)*))
+    };
+}
+
+/// Like eprintln!, for errors that stay in the host's own block when hosts run in parallel.
+macro_rules! ereport {
+    ($($arg:tt)*) => {
+        $crate::output::line(true, format!($($arg)*))
+    };
+}
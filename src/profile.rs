@@ -0,0 +1,443 @@
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+fn profiles_path() -> Option<PathBuf> {
+    // ~/.config/iot2050-telegraf/profiles.toml, or under %APPDATA% on Windows
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config) => PathBuf::from(config),
+        None => match std::env::var_os("APPDATA") {
+            Some(appdata) => PathBuf::from(appdata),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        },
+    };
+    Some(base.join("iot2050-telegraf").join("profiles.toml"))
+}
+
+fn read_profiles() -> Result<Table, Box<dyn std::error::Error>> {
+    let path = profiles_path().ok_or("unable to locate the home directory for profiles.toml")?;
+    if !path.exists() {
+        return Ok(Table::new());
+    }
+    let content = std::fs::read_to_string(&path)?;
+    toml::from_str(&content)
+        .map_err(|e| format!("{} is not valid TOML: {}", path.display(), e).into())
+}
+
+fn leaf<'a>(command: &'a Command, matches: &'a ArgMatches) -> (&'a Command, &'a ArgMatches) {
+    // The innermost subcommand holds every argument, global ones included once the command is built
+    match matches.subcommand() {
+        Some((name, sub_matches)) => match command.find_subcommand(name) {
+            Some(sub_command) => leaf(sub_command, sub_matches),
+            None => (command, matches),
+        },
+        None => (command, matches),
+    }
+}
+
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    // A flag on the command line or in the environment wins over the profile
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+fn first_pass(command: &Command, args: &[OsString]) -> ArgMatches {
+    // Required flags may come from a profile or config file, so this pass doesn't insist on them
+    command
+        .clone()
+        .ignore_errors(true)
+        .get_matches_from(args.to_vec())
+}
+
+fn fill_args(
+    leaf_command: &Command,
+    leaf_matches: &ArgMatches,
+    flags: &Table,
+    args: &mut Vec<OsString>,
+) {
+    // Turns `long-name = value` entries into flags, for those not given already
+    for (key, value) in flags {
+        // Flags this subcommand doesn't have are skipped, so one set can serve several subcommands
+        let Some(arg) = leaf_command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+        else {
+            continue;
+        };
+        if given(leaf_matches, arg.get_id().as_str()) {
+            continue;
+        }
+        let flag = OsString::from(format!("--{}", key));
+        let values: Vec<&Value> = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match (value, arg.get_action()) {
+                (Value::Boolean(true), ArgAction::SetTrue) => args.push(flag.clone()),
+                (Value::Boolean(false), ArgAction::SetTrue) => {}
+                (Value::String(value), _) => args.extend([flag.clone(), value.into()]),
+                (value, _) => args.extend([flag.clone(), value.to_string().into()]),
+            }
+        }
+    }
+}
+
+/// Adds the flags of the `--profile` named on the command line to `args`, leaving out those given already.
+pub fn apply_profile(mut command: Command, mut args: Vec<OsString>) -> Vec<OsString> {
+    command.build();
+    let matches = first_pass(&command, &args);
+    let (leaf_command, leaf_matches) = leaf(&command, &matches);
+    let Some(name) = leaf_matches.get_one::<String>("profile") else {
+        return args;
+    };
+
+    let profile = match read_profiles().map(|mut profiles| profiles.remove(name)) {
+        Ok(Some(Value::Table(profile))) => profile,
+        Ok(_) => {
+            eprintln!("Error: no profile named '{}' found.", name);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: unable to read the profiles: {}", e);
+            std::process::exit(1);
+        }
+    };
+    fill_args(leaf_command, leaf_matches, &profile, &mut args);
+    args
+}
+
+fn read_config_file(path: &str) -> Result<Table, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    toml::from_str(&content).map_err(|e| format!("not valid TOML: {}", e).into())
+}
+
+fn all_longs(command: &Command, longs: &mut Vec<String>) {
+    // Every flag name of the command and its subcommands, to tell a typo from a flag of another subcommand
+    for arg in command.get_arguments() {
+        if let Some(long) = arg.get_long() {
+            longs.push(long.to_string());
+        }
+    }
+    for sub_command in command.get_subcommands() {
+        all_longs(sub_command, longs);
+    }
+}
+
+/// Adds the flags of the `--config` file named on the command line to `args`, leaving out those given already.
+pub fn apply_config_file(mut command: Command, mut args: Vec<OsString>) -> Vec<OsString> {
+    command.build();
+    let matches = first_pass(&command, &args);
+    let (leaf_command, leaf_matches) = leaf(&command, &matches);
+    let Some(path) = leaf_matches.get_one::<String>("config") else {
+        return args;
+    };
+    let config = match read_config_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: unable to read the config file {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    // The file is checked in and reviewed, so a misspelled flag is an error rather than ignored
+    let mut longs = vec!["files".to_string(), "fields".to_string()];
+    all_longs(&command, &mut longs);
+    if let Some(key) = config.keys().find(|key| !longs.contains(key)) {
+        eprintln!(
+            "Error: unknown setting '{}' in the config file {}.",
+            key, path
+        );
+        std::process::exit(1);
+    }
+    fill_args(leaf_command, leaf_matches, &config, &mut args);
+    args
+}
+
+/// An answer for one XML file, given ahead in a `--config` file.
+pub struct FileAnswer {
+    pub file_name: String,
+    /// The prompt's setting, like `namespace number` or `root NodeId`.
+    pub setting: String,
+    pub answer: String,
+}
+
+/// Reads the `[fields]` of a `--config` file, node names mapped to the field names they get.
+pub fn field_names(path: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let config = read_config_file(path)?;
+    let Some(fields) = config.get("fields") else {
+        return Ok(HashMap::new());
+    };
+    let fields = fields
+        .as_table()
+        .ok_or("'fields' must be a table of node names")?;
+    fields
+        .iter()
+        .map(|(name, field)| match field.as_str() {
+            Some(field) if !field.is_empty() => Ok((name.clone(), field.to_string())),
+            _ => Err(format!("the field name of '{}' must be a non-empty string", name).into()),
+        })
+        .collect()
+}
+
+/// Reads the `[files."<name>.xml"]` answers of a `--config` file.
+pub fn file_answers(path: &str) -> Result<Vec<FileAnswer>, Box<dyn std::error::Error>> {
+    let config = read_config_file(path)?;
+    let Some(files) = config.get("files") else {
+        return Ok(Vec::new());
+    };
+    let files = files
+        .as_table()
+        .ok_or("'files' must be a table of XML file names")?;
+
+    let mut answers = Vec::new();
+    for (file_name, settings) in files {
+        let settings = settings
+            .as_table()
+            .ok_or_else(|| format!("the settings of '{}' must be a table", file_name))?;
+        for (key, value) in settings {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            if key == "interval" && !crate::format::is_valid_duration(&value) {
+                return Err(format!(
+                    "invalid interval '{}' for '{}', expecting a duration like 500ms or 1s",
+                    value, file_name
+                )
+                .into());
+            }
+            // A file is either a listener or not, the interval answers whichever prompt it gets
+            let settings: &[&str] = match key.as_str() {
+                "namespace" => &["namespace number"],
+                "interval" => &["interval", "sampling_interval"],
+                "root" => &["root NodeId"],
+                "bucket" => &["bucket"],
+                _ => {
+                    return Err(format!(
+                    "unknown setting '{}' for '{}', expecting namespace, interval, root or bucket",
+                    key, file_name
+                )
+                    .into())
+                }
+            };
+            for setting in settings {
+                answers.push(FileAnswer {
+                    file_name: file_name.clone(),
+                    setting: setting.to_string(),
+                    answer: value.clone(),
+                });
+            }
+        }
+    }
+    Ok(answers)
+}
+
+/// Writes the flags given on the command line to the `--save-profile` profile, replacing what it held.
+pub fn save_profile(
+    command: &Command,
+    matches: &ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut command = command.clone();
+    command.build();
+    let (leaf_command, leaf_matches) = leaf(&command, matches);
+    let Some(name) = leaf_matches.get_one::<String>("save_profile") else {
+        return Ok(());
+    };
+
+    let mut profile = Table::new();
+    for arg in leaf_command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches!(id, "profile" | "save_profile")
+            || leaf_matches.value_source(id) != Some(ValueSource::CommandLine)
+        {
+            continue;
+        }
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => Value::Boolean(true),
+            action => {
+                let values: Vec<Value> = leaf_matches
+                    .get_raw(id)
+                    .into_iter()
+                    .flatten()
+                    .map(|value| Value::String(value.to_string_lossy().into_owned()))
+                    .collect();
+                match (action, values.len()) {
+                    (ArgAction::Append, _) => Value::Array(values),
+                    (_, 1) => values.into_iter().next().unwrap(),
+                    _ => Value::Array(values),
+                }
+            }
+        };
+        profile.insert(long.to_string(), value);
+    }
+
+    let path = profiles_path().ok_or("unable to locate the home directory for profiles.toml")?;
+    let mut profiles = read_profiles()?;
+    profiles.insert(name.clone(), Value::Table(profile));
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, toml::to_string(&profiles)?)?;
+    // Profiles can hold passwords, keep them to the current user
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    say!("Saved profile '{}' to {}", name, path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("tool").subcommand(
+            Command::new("generate").args([
+                Arg::new("ip").long("ip"),
+                Arg::new("quiet").long("quiet").action(ArgAction::SetTrue),
+                Arg::new("yes").long("yes").action(ArgAction::SetTrue),
+                Arg::new("tag").long("tag").action(ArgAction::Append),
+                Arg::new("retries")
+                    .long("retries")
+                    .value_parser(clap::value_parser!(u32)),
+                Arg::new("config").long("config"),
+            ]),
+        )
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn filled(given: &[&str], flags: &str) -> Vec<OsString> {
+        let mut command = command();
+        command.build();
+        let mut given = args(given);
+        let matches = first_pass(&command, &given);
+        let (leaf_command, leaf_matches) = leaf(&command, &matches);
+        fill_args(
+            leaf_command,
+            leaf_matches,
+            &toml::from_str(flags).unwrap(),
+            &mut given,
+        );
+        given
+    }
+
+    fn temp_file(name: &str, content: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("profile_{}_{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn flags_are_turned_into_arguments() {
+        assert_eq!(
+            filled(
+                &["tool", "generate"],
+                "ip = \"10.0.0.1\"\nquiet = true\nyes = false\nretries = 3\ntag = [\"a=1\", \"b=2\"]",
+            ),
+            args(&[
+                "tool", "generate", "--ip", "10.0.0.1", "--quiet", "--retries", "3", "--tag",
+                "a=1", "--tag", "b=2",
+            ])
+        );
+    }
+
+    #[test]
+    fn given_and_unknown_flags_are_left_alone() {
+        assert_eq!(
+            filled(
+                &["tool", "generate", "--ip", "10.0.0.2"],
+                "ip = \"10.0.0.1\"\nbackup-since = \"2024-05-01T00:00:00Z\"",
+            ),
+            args(&["tool", "generate", "--ip", "10.0.0.2"])
+        );
+    }
+
+    #[test]
+    fn config_files_fill_in_flags() {
+        let path = temp_file(
+            "config",
+            "ip = \"10.0.0.1\"\n\n[files.\"a.xml\"]\nnamespace = 3\n",
+        );
+        let filled = apply_config_file(command(), args(&["tool", "generate", "--config", &path]));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            filled,
+            args(&["tool", "generate", "--config", &path, "--ip", "10.0.0.1"])
+        );
+    }
+
+    #[test]
+    fn file_answers_cover_their_prompts() {
+        let path = temp_file(
+            "answers",
+            "[files.\"a.xml\"]\nnamespace = 3\ninterval = \"500ms\"\nroot = \"ns=3;i=1000\"\n",
+        );
+        let answers = file_answers(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let answers: Vec<(&str, &str, &str)> = answers
+            .iter()
+            .map(|answer| {
+                (
+                    answer.file_name.as_str(),
+                    answer.setting.as_str(),
+                    answer.answer.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("a.xml", "interval", "500ms"),
+                ("a.xml", "sampling_interval", "500ms"),
+                ("a.xml", "namespace number", "3"),
+                ("a.xml", "root NodeId", "ns=3;i=1000"),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_file_answers_are_rejected() {
+        for (name, content) in [
+            ("interval", "[files.\"a.xml\"]\ninterval = \"soon\"\n"),
+            ("unknown", "[files.\"a.xml\"]\nnamspace = 3\n"),
+            ("not_a_table", "files = \"a.xml\"\n"),
+        ] {
+            let path = temp_file(name, content);
+            let answers = file_answers(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert!(answers.is_err(), "{} was accepted", content);
+        }
+    }
+
+    #[test]
+    fn field_names_are_read() {
+        let path = temp_file("fields", "[fields]\nTemp = \"oil_temperature\"\n");
+        let fields = field_names(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            fields.get("Temp").map(String::as_str),
+            Some("oil_temperature")
+        );
+
+        let path = temp_file("empty_field", "[fields]\nTemp = \"\"\n");
+        let fields = field_names(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(fields.is_err());
+    }
+}
@@ -1,260 +1,1421 @@
-use ssh2::Session;
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use ssh2::{Channel, CheckResult, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session};
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use std::io::{IsTerminal, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Keeps hosts that are connected to in parallel from asking about their keys at the same time.
+static HOST_KEY_PROMPT: Mutex<()> = Mutex::new(());
+
+/// Where and as whom to connect, and whether the host key is checked.
+#[derive(Clone)]
+pub struct SshTarget {
+    pub host: String,
+    pub username: String,
+    pub password: String,
+    /// Private key to log in with, the password is only used when this is unset.
+    pub identity: Option<PathBuf>,
+    /// Skips the known_hosts check and trusts whatever key the host presents.
+    pub insecure: bool,
+    /// Limit for reaching the host and for each read or write while the session is set up.
+    pub timeout: Duration,
+    /// How many more times to try when the host can't be reached or the handshake drops.
+    pub retries: u32,
+    /// Uploads over SFTP instead of SCP, for servers that have SCP turned off.
+    pub sftp: bool,
+}
+
+/// The host couldn't be reached or dropped the handshake, so nothing ran on it yet.
+#[derive(Debug)]
+pub struct Unreachable(String);
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// How Telegraf is restarted on the box and how to tell whether it came back up.
+pub struct RestartCommand {
+    pub restart: String,
+    /// Exits with 0 while Telegraf is running, the check is skipped when unset.
+    pub status: Option<String>,
+    /// How many lines of the Telegraf log to show.
+    pub log_lines: u32,
+    /// Also shows the log after a successful restart, not only after a failed one.
+    pub verbose: bool,
+    /// How long Telegraf may take to be running after the restart.
+    pub timeout: Duration,
+}
+
+/// Caps the average speed of downloads, so a backup over a metered link leaves room for live data.
+struct RateLimit {
+    /// Unlimited when unset.
+    bytes_per_second: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimit {
+    fn new(kilobytes_per_second: Option<u64>) -> Self {
+        RateLimit {
+            bytes_per_second: kilobytes_per_second.map(|rate| rate * 1024),
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` more and sleeps until everything so far fits into the rate.
+    fn pace(&mut self, bytes: usize) {
+        let Some(rate) = self.bytes_per_second else {
+            return;
+        };
+        self.bytes += bytes as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// A reader that keeps to a rate limit, shared by the files of one download.
+struct Throttled<'a, R> {
+    inner: R,
+    limit: &'a mut RateLimit,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.limit.pace(count);
+        Ok(count)
+    }
+}
+
+impl RestartCommand {
+    /// Whether the usual systemd unit status and journal hints apply.
+    fn is_systemd(&self) -> bool {
+        self.restart.contains("systemctl")
+    }
+}
+
+fn open_session(
+    // Connects to the remote host, verifies its key and returns an authenticated session
+    target: &SshTarget,
+) -> Result<Session, Box<dyn std::error::Error>> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        // Only reaching the host is retried, a rejected key or password won't fix itself
+        let (tcp, session) = match connect_session(target) {
+            Ok(connected) => connected,
+            Err(e) if attempt < target.retries => {
+                attempt += 1;
+                say!(
+                    "{}, retrying in {}s (attempt {} of {}) ..",
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    target.retries
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                continue;
+            }
+            Err(e) => return Err(Box::new(Unreachable(e))),
+        };
+
+        if target.insecure {
+            log::debug!("Skipping the host key check of {}", target.host);
+        } else {
+            verify_host_key(&session, &target.host)?;
+        }
+        match &target.identity {
+            Some(identity) => {
+                log::debug!(
+                    "Logging in to {} as {} with the key {}",
+                    target.host,
+                    target.username,
+                    identity.display()
+                );
+                session.userauth_pubkey_file(&target.username, None, identity, None)?
+            }
+            None => {
+                log::debug!(
+                    "Logging in to {} as {} with a password",
+                    target.host,
+                    target.username
+                );
+                session.userauth_password(&target.username, &target.password)?
+            }
+        }
+        log::info!("Connected to {}", target.host);
+
+        // Commands like influx backup stay silent for a long time, only writes keep their limit
+        session.set_timeout(0);
+        tcp.set_read_timeout(None)?;
+        return Ok(session);
+    }
+}
+
+fn connect_session(target: &SshTarget) -> Result<(TcpStream, Session), String> {
+    // Opens the connection and runs the SSH handshake, both within the timeout
+    let unreachable = |e: &dyn std::fmt::Display| format!("could not reach {}: {}", target.host, e);
+    log::debug!(
+        "Connecting to {} with a {}s timeout",
+        target.host,
+        target.timeout.as_secs()
+    );
+    let tcp = connect(&target.host, target.timeout).map_err(|e| unreachable(&e))?;
+    tcp.set_read_timeout(Some(target.timeout))
+        .and_then(|_| tcp.set_write_timeout(Some(target.timeout)))
+        .map_err(|e| unreachable(&e))?;
+    let mut session = Session::new().map_err(|e| unreachable(&e))?;
+    // libssh2 waits on the socket itself, so it needs the limit as well
+    session.set_timeout(target.timeout.as_millis().try_into().unwrap_or(u32::MAX));
+    session.set_tcp_stream(tcp.try_clone().map_err(|e| unreachable(&e))?);
+    session.handshake().map_err(|e| unreachable(&e))?;
+    log::debug!("SSH handshake with {} done", target.host);
+    Ok((tcp, session))
+}
+
+fn connect(remote_host: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    // Tries every address the host resolves to, each within the timeout
+    let mut last_error = None;
+    for address in remote_host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")))
+}
+
+fn known_hosts_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".ssh").join("known_hosts"))
+}
+
+fn base64(bytes: &[u8], padded: bool) -> String {
+    // Standard base64, OpenSSH pads key blobs in known_hosts but leaves SHA256 fingerprints unpadded
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+        if padded {
+            for _ in chunk.len()..3 {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn append_known_host(
+    // Appends an OpenSSH known_hosts line, the key blob starts with its own algorithm name
+    path: &Path,
+    entry: &str,
+    key: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name_len = key
+        .get(..4)
+        .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+        .ok_or("malformed host key")?;
+    let algorithm = key
+        .get(4..4 + name_len)
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .ok_or("malformed host key")?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{} {} {}", entry, algorithm, base64(key, true))?;
+    Ok(())
+}
+
+fn verify_host_key(session: &Session, remote_host: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // Checks the host key against ~/.ssh/known_hosts, offering to trust hosts seen for the first time
+    let (host, port) = match remote_host.rsplit_once(':') {
+        Some((host, port)) => (host.trim_matches(['[', ']']), port.parse().unwrap_or(22)),
+        None => (remote_host, 22),
+    };
+    let (key, _) = session.host_key().ok_or("the host did not present a key")?;
+    let fingerprint = session
+        .host_key_hash(ssh2::HashType::Sha256)
+        .map(|hash| format!("SHA256:{}", base64(hash, false)))
+        .unwrap_or_default();
+
+    let path = known_hosts_path().ok_or("unable to locate the home directory for known_hosts")?;
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(format!(
+            "the host key of {} does not match the one in {} (got {}), refusing to connect. \
+             If the device was replaced, remove its old entry or pass --insecure",
+            remote_host,
+            path.display(),
+            fingerprint
+        )
+        .into()),
+        CheckResult::NotFound => {
+            // Nobody is there to answer in quiet mode
+            if crate::output::is_quiet() {
+                return Err(format!(
+                    "host key of {} is unknown, connect once without --quiet to check and add it",
+                    remote_host
+                )
+                .into());
+            }
+            crate::output::ask(
+                &format!("whether to trust the host key of {}", remote_host),
+                "connect once without --no-input to check and add it, or pass --insecure",
+            )?;
+            // Asked right away even while other hosts are being handled, one question at a time
+            let _asking = HOST_KEY_PROMPT
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            println!(
+                "The authenticity of host '{}' can't be established.\nKey fingerprint is {}.",
+                remote_host, fingerprint
+            );
+            println!("Do you want to trust this host and add it to known_hosts? (y/N)");
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                return Err(format!("host key of {} was not accepted", remote_host).into());
+            }
+
+            // OpenSSH writes non-default ports as [host]:port
+            let entry = if port == 22 {
+                host.to_string()
+            } else {
+                format!("[{}]:{}", host, port)
+            };
+            append_known_host(&path, &entry, key)?;
+            say!("Added {} to {}", entry, path.display());
+            Ok(())
+        }
+        CheckResult::Failure => {
+            Err(format!("unable to check the host key of {}", remote_host).into())
+        }
+    }
+}
+
+fn read_command_output(
+    // Runs a command on an open session and returns everything it printed to stdout
+    session: &Session,
+    command: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    log::debug!("Running '{}'", command);
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    session.set_blocking(false);
+    let read = read_stdout_and_stderr(&mut channel);
+    session.set_blocking(true);
+    let (stdout, _) = read?;
+    channel.wait_close()?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Exit status and captured output of a command run over SSH.
+struct CommandOutput {
+    status: i32,
+    stdout: String,
+    stderr: String,
+}
+
+fn read_stdout_and_stderr(channel: &mut Channel) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    // Reads both streams in turn on a non-blocking session. Reading stdout to the end first
+    // would hang once unread stderr fills the channel window and the command waits to write more.
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut buffer = [0u8; 32 * 1024];
+    loop {
+        let mut progressed = false;
+        for to_stderr in [false, true] {
+            let read = if to_stderr {
+                channel.stderr().read(&mut buffer)
+            } else {
+                channel.read(&mut buffer)
+            };
+            match read {
+                Ok(0) => {}
+                Ok(count) => {
+                    let output = if to_stderr { &mut stderr } else { &mut stdout };
+                    output.extend_from_slice(&buffer[..count]);
+                    progressed = true;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !progressed {
+            if channel.eof() {
+                return Ok((stdout, stderr));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn run_command(
+    // Runs a command on an open session and captures its exit status, stdout and stderr
+    session: &Session,
+    command: &str,
+) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+    log::debug!("Running '{}'", command);
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    session.set_blocking(false);
+    let read = read_stdout_and_stderr(&mut channel);
+    session.set_blocking(true);
+    let (stdout, stderr) = read?;
+    let stdout = String::from_utf8_lossy(&stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&stderr).into_owned();
+    channel.wait_close()?;
+    let status = channel.exit_status()?;
+    log::debug!("'{}' exited with code {}", command, status);
+    log::trace!("stdout: {:?}", stdout);
+    log::trace!("stderr: {:?}", stderr);
+    Ok(CommandOutput {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+fn command_failed(command: &str, output: &CommandOutput) -> String {
+    // Describes a failed command with whatever it printed, stderr first since that's where errors go
+    let mut message = format!("'{}' exited with code {}", command, output.status);
+    for captured in [&output.stderr, &output.stdout] {
+        if !captured.trim().is_empty() {
+            message.push_str(&format!(": {}", captured.trim()));
+        }
+    }
+    message
+}
+
+fn shell_quote(value: &str) -> String {
+    // Wraps a value in single quotes so the remote shell passes it through unchanged
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
 
 pub fn send_and_restart_telegraf(
     config_path: &Path,
     remote_path: &str,
-    iot_host: &str,
-    iot_username: &str,
-    iot_password: &str,
+    target: &SshTarget,
+    keep_backup: bool,
+    restart: &RestartCommand,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Send the telegraf.conf file to the IOT box
-    send_file_over_ssh(
-        config_path,
+    // Send and restart share a single connection to the IOT box
+    let session = open_session(target)?;
+
+    // Copy the current config aside, it is also what gets restored if the new one is rejected
+    let backup_path = format!(
+        "{}.bak-{}",
         remote_path,
-        iot_host,
-        iot_username,
-        iot_password,
-    )?;
+        chrono::Utc::now().format("%Y-%m-%d_%H%M%S")
+    );
+    let has_backup = run_command(
+        &session,
+        &format!(
+            "cp -p {} {}",
+            shell_quote(remote_path),
+            shell_quote(&backup_path)
+        ),
+    )?
+    .status
+        == 0;
+    if has_backup && keep_backup {
+        say!("Backed up the current config to {}", backup_path);
+    }
+
+    // Send the telegraf.conf file to the IOT box, then let telegraf parse it before the running service is touched
+    let sent = send_file(&session, config_path, remote_path, 0o644, target.sftp)
+        .and_then(|_| validate_telegraf_config(&session, remote_path));
+    if let Err(e) = sent {
+        if has_backup {
+            let restore = if keep_backup { "cp -p" } else { "mv" };
+            run_command(
+                &session,
+                &format!(
+                    "{} {} {}",
+                    restore,
+                    shell_quote(&backup_path),
+                    shell_quote(remote_path)
+                ),
+            )?;
+            say!("Restored the previous config, Telegraf was not restarted.");
+        }
+        return Err(e);
+    }
+    if has_backup && !keep_backup {
+        run_command(&session, &format!("rm -f {}", shell_quote(&backup_path)))?;
+    }
 
     // Restart the telegraf service on the IOT box
-    restart_telegraf_over_ssh(iot_host, iot_username, iot_password)?;
+    restart_telegraf(&session, restart)?;
 
     Ok(())
 }
 
+pub fn rollback_telegraf_config(
+    remote_path: &str,
+    target: &SshTarget,
+    choose: impl FnOnce(&[String]) -> usize,
+    restart: &RestartCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Puts one of the copies kept by send back in place, chosen from the newest down
+    let session = open_session(target)?;
+    let listing = run_command(
+        &session,
+        &format!("ls -1d {}.bak-* 2>/dev/null", shell_quote(remote_path)),
+    )?;
+    // The timestamp in the name sorts the same way as the time itself
+    let mut backups: Vec<String> = listing
+        .stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    backups.sort_unstable_by(|a, b| b.cmp(a));
+    if backups.is_empty() {
+        return Err(format!("no backups of {} found on {}", remote_path, target.host).into());
+    }
+    log::debug!("Found {} backups of {}", backups.len(), remote_path);
+
+    let backup = &backups[choose(&backups)];
+    // cp rather than mv keeps the backup around, so the same rollback can run again
+    let restored = run_command(
+        &session,
+        &format!("cp -p {} {}", shell_quote(backup), shell_quote(remote_path)),
+    )?;
+    if restored.status != 0 {
+        return Err(format!(
+            "could not restore {}: {}",
+            backup,
+            restored.stderr.trim_end()
+        )
+        .into());
+    }
+    say!("Restored {} over {}", backup, remote_path);
+
+    restart_telegraf(&session, restart)
+}
+
+/// The config the Telegraf service loads, the agent settings and outputs of a drop-in live there.
+pub const MAIN_CONFIG_PATH: &str = "/etc/telegraf/telegraf.conf";
+/// How long `telegraf --test` may take, parse errors show up long before.
+const VALIDATE_TIMEOUT_SECS: u64 = 30;
+/// The exit code of `timeout` when it stopped the command.
+const TIMED_OUT: i32 = 124;
+
+fn validate_telegraf_config(
+    session: &Session,
+    remote_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // --test also gathers every input once, which hangs while an OPC UA server is unreachable.
+    // A config that loaded is all this checks for, so that isn't waited on for long.
+    // A drop-in only holds inputs, so it is loaded the way the service does, next to the main config
+    let configs = if remote_path == MAIN_CONFIG_PATH {
+        format!("--config {}", shell_quote(remote_path))
+    } else {
+        let directory = Path::new(remote_path)
+            .parent()
+            .and_then(Path::to_str)
+            .filter(|directory| !directory.is_empty())
+            .unwrap_or(".");
+        format!(
+            "--config {} --config-directory {}",
+            shell_quote(MAIN_CONFIG_PATH),
+            shell_quote(directory)
+        )
+    };
+    say!("Validating the config with telegraf --test ..");
+    let output = run_command(
+        session,
+        &format!(
+            "timeout {} telegraf {} --test --test-wait 0",
+            VALIDATE_TIMEOUT_SECS, configs
+        ),
+    )?;
+    if output.status == TIMED_OUT {
+        say!(
+            "telegraf --test loaded the config but didn't finish gathering within {}s, an input may not be reachable yet.",
+            VALIDATE_TIMEOUT_SECS
+        );
+        return Ok(());
+    }
+    if output.status != 0 {
+        // Parse errors land on stderr, fall back to stdout in case a build reports them there
+        let details = if output.stderr.trim().is_empty() {
+            &output.stdout
+        } else {
+            &output.stderr
+        };
+        ereport!("Telegraf rejected the config:\n{}", details.trim_end());
+        return Err(format!(
+            "config validation failed (telegraf exited with code {})",
+            output.status
+        )
+        .into());
+    }
+    say!("Config validated successfully.");
+    Ok(())
+}
+
 pub fn send_file_over_ssh(
     // Sends a file over SSH to a specified remote host, path, and credentials
     local_path: &Path,
     remote_path: &str,
-    remote_host: &str,
-    username: &str,
-    password: &str,
+    target: &SshTarget,
+    mode: i32,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Sending file ..");
-    // Establish a TCP connection to the remote host
-    let tcp = TcpStream::connect(remote_host)?;
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
+    let session = open_session(target)?;
+    send_file(&session, local_path, remote_path, mode, target.sftp)
+}
 
-    // Authenticate with the remote server
-    session.userauth_password(username, password)?;
+/// Sends a private key readable by its owner only, handing it to the telegraf user when the box
+/// has one, since Telegraf doesn't run as the login user.
+pub fn send_private_key_over_ssh(
+    local_path: &Path,
+    remote_path: &str,
+    target: &SshTarget,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session = open_session(target)?;
+    send_file(&session, local_path, remote_path, 0o600, target.sftp)?;
+    let path = shell_quote(remote_path);
+    let command = format!(
+        "if id telegraf >/dev/null 2>&1; then chown telegraf {path} 2>/dev/null || sudo -n chown telegraf {path}; fi"
+    );
+    let output = run_command(&session, &command)?;
+    if output.status != 0 {
+        return Err(format!(
+            "unable to hand {} to the telegraf user: {}",
+            remote_path,
+            command_failed(&command, &output)
+        )
+        .into());
+    }
+    Ok(())
+}
 
+fn send_file(
+    session: &Session,
+    local_path: &Path,
+    remote_path: &str,
+    mode: i32,
+    sftp: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    say!("Sending file ..");
+    log::debug!(
+        "Uploading {} to {} with mode {:o} over {}",
+        local_path.display(),
+        remote_path,
+        mode,
+        if sftp { "SFTP" } else { "SCP" }
+    );
+    if sftp {
+        return send_file_over_sftp(session, local_path, remote_path, mode);
+    }
     // Open a new SCP session and send the file
     let mut remote_file = session.scp_send(
         Path::new(remote_path),
-        0o644,
+        mode,
         local_path.metadata()?.len(),
         None,
     )?;
-    let mut local_file = std::fs::File::open(local_path)?;
+    let mut local_file = HashingReader::new(File::open(local_path)?);
+    std::io::copy(&mut local_file, &mut remote_file)?;
 
-    let mut contents = Vec::new();
-    local_file.read_to_end(&mut contents)?;
-    remote_file.write_all(&contents)?;
+    // Close the SCP channel so the upload is complete before the session is reused
+    remote_file.send_eof()?;
+    remote_file.wait_eof()?;
+    remote_file.close()?;
+    remote_file.wait_close()?;
 
-    Ok(())
+    verify_checksum(session, &local_file.digest(), remote_path)
 }
 
-pub fn restart_telegraf_over_ssh(
-    remote_host: &str,
-    username: &str,
-    password: &str,
+fn send_file_over_sftp(
+    // Streams the file through an SFTP handle for servers that have SCP turned off
+    session: &Session,
+    local_path: &Path,
+    remote_path: &str,
+    mode: i32,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Restarting telegraf service on the remote host ..");
-    let tcp = TcpStream::connect(remote_host)?;
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
-    session.userauth_password(username, password)?;
-
-    // Restart the service
-    let mut channel = session.channel_session()?;
-    channel.exec("sudo systemctl restart telegraf")?;
-    channel.send_eof()?;
-    channel.wait_eof()?;
-    channel.wait_close()?;
+    let sftp = session.sftp()?;
+    let mut remote_file = sftp.open_mode(
+        Path::new(remote_path),
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        mode,
+        OpenType::File,
+    )?;
+    let mut local_file = HashingReader::new(File::open(local_path)?);
+    std::io::copy(&mut local_file, &mut remote_file)?;
+    drop(remote_file);
+
+    // The mode only applies to new files, an existing one keeps its old bits otherwise
+    sftp.setstat(
+        Path::new(remote_path),
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(mode as u32),
+            atime: None,
+            mtime: None,
+        },
+    )?;
+
+    verify_checksum(session, &local_file.digest(), remote_path)
+}
 
-    // Wait for a few seconds to allow the service to start
-    println!("Waiting for the service to start ..");
-    thread::sleep(Duration::from_secs(5));
+/// Hashes what passes through it, so an upload can be streamed and still checked afterwards.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
 
-    // Check the status of the service
-    let mut status_channel = session.channel_session()?;
-    status_channel
-        .exec("systemctl is-active --quiet telegraf && echo 'active' || echo 'failed'")?;
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
 
-    let mut status = String::new();
-    status_channel.read_to_string(&mut status)?;
-    status_channel.wait_close()?;
+    /// The SHA-256 of everything read so far, in the lowercase hex sha256sum prints.
+    fn digest(&self) -> String {
+        self.hasher
+            .clone()
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
 
-    let status = status.trim();
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.hasher.update(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+fn verify_checksum(
+    // Compares the SHA-256 of what was sent with the file that arrived, a truncated upload fails here
+    session: &Session,
+    expected: &str,
+    remote_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let command = format!("sha256sum {}", shell_quote(remote_path));
+    let output = run_command(session, &command)?;
+    if output.status != 0 {
+        return Err(command_failed(&command, &output).into());
+    }
+    let actual = output.stdout.split_whitespace().next().unwrap_or_default();
+    log::debug!(
+        "SHA-256 of {}: sent {}, arrived {}",
+        remote_path,
+        expected,
+        actual
+    );
+    if actual != expected {
+        return Err(format!(
+            "{} arrived damaged, its SHA-256 is {} instead of {}",
+            remote_path, actual, expected
+        )
+        .into());
+    }
+    say!("Checksum of {} verified.", remote_path);
+    Ok(())
+}
+
+fn restart_telegraf(
+    session: &Session,
+    restart: &RestartCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    say!("Restarting telegraf service on the remote host ..");
+
+    // Restart the service, a failing custom command still gets its status checked below
+    let output = run_command(session, &restart.restart)?;
+    let restarted = output.status == 0;
+    if !restarted {
+        report!("Warning: {}", command_failed(&restart.restart, &output));
+    }
+
+    let status_command = match &restart.status {
+        Some(status_command) => status_command,
+        None if restarted => {
+            say!("No status command set, not checking whether Telegraf is running.");
+            return Ok(());
+        }
+        None => return Err(command_failed(&restart.restart, &output).into()),
+    };
+
+    // Check every second until the service runs, slow boxes take a while to connect to the OPC servers
+    say!("Waiting for the service to start ..");
+    let started = Instant::now();
+    let status = loop {
+        let status = telegraf_status(session, status_command)?;
+        if status == "active" || started.elapsed() >= restart.timeout {
+            break status;
+        }
+        thread::sleep(Duration::from_secs(1));
+    };
+    log::debug!(
+        "Telegraf status '{}' after {:.1}s",
+        status,
+        started.elapsed().as_secs_f64()
+    );
 
     if status == "active" {
-        println!(
+        report!(
             "Telegraf service restarted successfully. Current status: {}",
             status
         );
+        if restart.verbose {
+            let logs = telegraf_log_tail(session, restart.log_lines)?;
+            report!("Recent Telegraf logs:\n\n{}", logs);
+        }
     } else {
-        println!(
-            "Telegraf service restarted, but it's not active. Current status: {}",
+        ereport!(
+            "Telegraf service restarted, but it's not active after {}s. Current status: {}",
+            restart.timeout.as_secs(),
             status
         );
+        print_telegraf_diagnostics(session, restart)?;
+        return Err(format!("Telegraf is not running after the restart ({})", status).into());
+    }
 
-        // Get more detailed status information
-        let mut detailed_status_channel = session.channel_session()?;
-        detailed_status_channel.exec("sudo systemctl status telegraf")?;
+    Ok(())
+}
 
-        let mut detailed_status = String::new();
-        detailed_status_channel.read_to_string(&mut detailed_status)?;
-        detailed_status_channel.wait_close()?;
+#[allow(dead_code)] // kept as a standalone entry point
+pub fn restart_telegraf_over_ssh(
+    // Restarts Telegraf on the remote host and checks that it came back up
+    target: &SshTarget,
+    restart: &RestartCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session = open_session(target)?;
+    restart_telegraf(&session, restart)
+}
 
-        println!("Detailed Telegraf status:\n(.__. )\n{}", detailed_status);
+fn telegraf_status(
+    session: &Session,
+    status_command: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let status = run_command(session, status_command)?.status;
+    Ok(if status == 0 { "active" } else { "failed" }.to_string())
+}
 
-        // Get the last 20 log entries for the Telegraf service
-        println!("Fetching recent logs for the Telegraf service ..");
-        let mut log_channel = session.channel_session()?;
-        log_channel.exec("tail -n 20 /var/log/telegraf/telegraf.log")?;
+fn telegraf_log_tail(session: &Session, lines: u32) -> Result<String, Box<dyn std::error::Error>> {
+    // The most recent lines of the Telegraf log file
+    read_command_output(
+        session,
+        &format!("tail -n {} /var/log/telegraf/telegraf.log", lines),
+    )
+}
 
-        let mut logs = String::new();
-        log_channel.read_to_string(&mut logs)?;
-        log_channel.wait_close()?;
+fn print_telegraf_diagnostics(
+    session: &Session,
+    restart: &RestartCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Only called when the restart failed, so it goes to stderr and shows under --quiet too.
+    // Get more detailed status information, only systemd can give it
+    if restart.is_systemd() {
+        let detailed_status = read_command_output(session, "sudo systemctl status telegraf")?;
+        ereport!("Detailed Telegraf status:\n(.__. )\n{}", detailed_status);
+    }
+
+    // Get the last log entries for the Telegraf service
+    say!("Fetching recent logs for the Telegraf service ..");
+    let logs = telegraf_log_tail(session, restart.log_lines)?;
+    ereport!("Recent Telegraf logs:\n( .__.)\n\n{}", logs);
+
+    // Get the last error entries among them
+    let error_logs = logs
+        .lines()
+        .filter(|line| line.contains("E!"))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if !error_logs.is_empty() {
+        ereport!("Latest Telegraf error logs:\n( *__*)\n\n{}", error_logs);
+    } else {
+        ereport!("No recent error logs found for Telegraf.");
+    }
+
+    Ok(())
+}
+
+const BACKUP_PREFIX: &str = "influx_backup_";
 
-        println!("Recent Telegraf logs:\n( .__.)\n\n{}", logs);
+/// Left in a file by file backup until every file is copied, so the next run can resume it.
+const INCOMPLETE_MARKER: &str = ".incomplete";
 
-        // Get the last error entry for the Telegraf service
-        let mut error_channel = session.channel_session()?;
-        error_channel.exec("tail -n 10 /var/log/telegraf/telegraf.log | grep 'E!'")?;
+#[allow(clippy::too_many_arguments)]
+pub fn backup_influxdb(
+    target: &SshTarget,
+    local_directory: &Path,
+    compress: bool,
+    extract: bool,
+    keep: Option<u32>,
+    force: bool,
+    max_rate: Option<u64>,
+    since: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    backup_influxdb_files(
+        target,
+        local_directory,
+        compress,
+        extract,
+        force,
+        max_rate,
+        since,
+    )?;
+    if let Some(keep) = keep {
+        rotate_backups(local_directory, keep)?;
+    }
+    Ok(())
+}
 
-        let mut error_logs = String::new();
-        error_channel.read_to_string(&mut error_logs)?;
-        error_channel.wait_close()?;
+fn rotate_backups(local_directory: &Path, keep: u32) -> Result<(), Box<dyn std::error::Error>> {
+    // Keeps the newest backups by the timestamp in their names, an archive and its extracted folder count as one
+    let mut backups: Vec<(String, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(local_directory)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if let Some(stamp) = name.strip_prefix(BACKUP_PREFIX) {
+            let stamp = stamp.strip_suffix(".tar.gz").unwrap_or(stamp).to_string();
+            backups.push((stamp, path));
+        }
+    }
+    let mut stamps: Vec<&String> = backups.iter().map(|(stamp, _)| stamp).collect();
+    stamps.sort_unstable_by(|a, b| b.cmp(a));
+    stamps.dedup();
+    let kept: Vec<String> = stamps.into_iter().take(keep as usize).cloned().collect();
 
-        if !error_logs.is_empty() {
-            println!("Latest Telegraf error logs:\n( *__*)\n\n{}", error_logs);
+    for (stamp, path) in &backups {
+        if kept.contains(stamp) {
+            continue;
+        }
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)?;
         } else {
-            println!("No recent error logs found for Telegraf.");
+            std::fs::remove_file(path)?;
         }
+        say!("Removed the old backup {}", path.display());
+    }
+    Ok(())
+}
+
+fn unfinished_backup(local_directory: &Path) -> Option<String> {
+    // The timestamp of the newest local file by file backup whose copy didn't finish
+    let mut stamps: Vec<String> = std::fs::read_dir(local_directory)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(INCOMPLETE_MARKER).exists())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.strip_prefix(BACKUP_PREFIX).map(str::to_string)
+        })
+        .collect();
+    stamps.sort_unstable();
+    stamps.pop()
+}
+
+fn backup_influxdb_files(
+    target: &SshTarget,
+    local_directory: &Path,
+    compress: bool,
+    extract: bool,
+    force: bool,
+    max_rate: Option<u64>,
+    since: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The backup and the download share a single connection
+    let session = open_session(target)?;
+
+    // An interrupted file by file copy is picked up again while its backup is still on the box
+    let resumed = match unfinished_backup(local_directory) {
+        Some(stamp) if !compress && !force => {
+            let remote = format!("/tmp/{}{}", BACKUP_PREFIX, stamp);
+            let exists = run_command(&session, &format!("test -d {}", shell_quote(&remote)))?;
+            (exists.status == 0).then_some(stamp)
+        }
+        _ => None,
+    };
+    // Down to the second, so a second backup on the same day doesn't overwrite the first
+    let date = resumed
+        .clone()
+        .unwrap_or_else(|| chrono::Utc::now().format("%Y-%m-%d_%H%M%S").to_string());
+    let backup_folder = format!("/tmp/{}{}", BACKUP_PREFIX, date);
+    let mut backup_command = String::from("influx backup -p /var/lib/influxdb2");
+    if let Some(since) = since {
+        // Only data written after this time goes into the backup
+        backup_command.push_str(&format!(" --start {}", shell_quote(since)));
+    }
+    backup_command.push_str(&format!(" {}", backup_folder));
+
+    if resumed.is_some() {
+        say!(
+            "Resuming the unfinished backup {}, add --force to start a new one",
+            backup_folder
+        );
+    } else {
+        if let Some(since) = since {
+            check_backup_since(&session, since)?;
+        }
+        match since {
+            Some(since) => say!(
+                "Backing up InfluxDB data since {} to {}",
+                since,
+                backup_folder
+            ),
+            None => say!("Backing up InfluxDB to {}", backup_folder),
+        }
+        execute_command(&session, &backup_command)?;
+    }
+
+    let local_backup_path = local_directory.join(format!("{}{}", BACKUP_PREFIX, date));
+    if !compress {
+        let marker = local_backup_path.join(INCOMPLETE_MARKER);
+        std::fs::create_dir_all(&local_backup_path)?;
+        File::create(&marker)?;
+        copy_directory(
+            &session,
+            &backup_folder,
+            &local_backup_path.display().to_string(),
+            force,
+            max_rate,
+        )?;
+        std::fs::remove_file(&marker)?;
+        // Only an unfinished copy needs the backup on the box, to resume from it
+        remove_remote_backup(&session, &[&backup_folder])?;
+        report!(
+            "Backup completed successfully. Files are located at: {}",
+            local_backup_path.display()
+        );
+        return Ok(());
+    }
+
+    // One archive instead of many small files saves a round trip per file
+    let remote_archive = format!("{}.tar.gz", backup_folder);
+    say!("Compressing the backup to {} ..", remote_archive);
+    let output = run_command(
+        &session,
+        &format!(
+            "tar czf {} -C {} .",
+            shell_quote(&remote_archive),
+            shell_quote(&backup_folder)
+        ),
+    )?;
+    if output.status != 0 {
+        return Err(format!("failed to compress the backup: {}", output.stderr.trim()).into());
     }
 
+    let local_archive = local_directory.join(format!("{}{}.tar.gz", BACKUP_PREFIX, date));
+    std::fs::create_dir_all(local_directory)?;
+    let received = receive_file(&session, &remote_archive, &local_archive, max_rate);
+    // Archives can't be resumed, so nothing is left in /tmp whether the download worked or not
+    remove_remote_backup(&session, &[&backup_folder, &remote_archive])?;
+    received?;
+    report!(
+        "Backup completed successfully. Archive is located at: {}",
+        local_archive.display()
+    );
+
+    if extract {
+        std::fs::create_dir_all(&local_backup_path)?;
+        let status = std::process::Command::new("tar")
+            .arg("xzf")
+            .arg(&local_archive)
+            .arg("-C")
+            .arg(&local_backup_path)
+            .status()?;
+        if !status.success() {
+            return Err(
+                format!("failed to extract {} ({})", local_archive.display(), status).into(),
+            );
+        }
+        say!("Extracted the archive to {}", local_backup_path.display());
+    }
     Ok(())
 }
 
-pub fn backup_influxdb(
-    iot_host: &str,
-    iot_username: &str,
-    iot_password: &str,
+fn check_backup_since(session: &Session, since: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // The influx CLI would only say it doesn't know the flag after connecting to the database
+    chrono::DateTime::parse_from_rfc3339(since)
+        .map_err(|_| format!("'{}' is not an RFC3339 timestamp", since))?;
+    let help = run_command(session, "influx backup --help 2>&1")?;
+    if !help.stdout.contains("--start") {
+        return Err(
+            "the influx CLI on the IOT-2050 doesn't support --start, which --backup-since needs, \
+             update it or back up without --backup-since"
+                .into(),
+        );
+    }
+    Ok(())
+}
+
+fn remove_remote_backup(
+    session: &Session,
+    paths: &[&str],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let date = chrono::Utc::now().format("%Y-%m-%d").to_string();
-    let backup_folder = format!("/tmp/influx_backup_{}", date);
-    let backup_command = format!("influx backup -p /var/lib/influxdb2 {}", backup_folder);
-
-    println!("Backing up InfluxDB to {}", backup_folder);
-    execute_command_over_ssh(iot_host, iot_username, iot_password, &backup_command)?;
-
-    let local_backup_path = format!("./influx_backup_{}", date);
-    std::fs::create_dir_all(&local_backup_path)?;
-    copy_directory_over_ssh(
-        iot_host,
-        iot_username,
-        iot_password,
-        &backup_folder,
-        &local_backup_path,
+    // A copy of the database shouldn't stay in /tmp once it is downloaded
+    let quoted: Vec<String> = paths.iter().map(|path| shell_quote(path)).collect();
+    let output = run_command(session, &format!("rm -rf {}", quoted.join(" ")))?;
+    if output.status != 0 {
+        report!(
+            "Warning: could not remove {} on the IOT-2050: {}",
+            paths.join(" "),
+            output.stderr.trim()
+        );
+    } else {
+        log::debug!("Removed {}", paths.join(" "));
+    }
+    Ok(())
+}
+
+pub fn restore_influxdb(
+    target: &SshTarget,
+    backup_path: &Path,
+    full: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Extracted backups are packed up first so they travel as a single file like the downloads did
+    if !backup_path.is_dir() {
+        return restore_influxdb_archive(target, backup_path, full);
+    }
+    let archive =
+        std::env::temp_dir().join(format!("influx_restore_{}.tar.gz", std::process::id()));
+    let status = std::process::Command::new("tar")
+        .arg("czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(backup_path)
+        .arg(".")
+        .status()?;
+    if !status.success() {
+        return Err(format!("failed to compress {} ({})", backup_path.display(), status).into());
+    }
+    let result = restore_influxdb_archive(target, &archive, full);
+    let _ = std::fs::remove_file(&archive);
+    result
+}
+
+fn restore_influxdb_archive(
+    target: &SshTarget,
+    archive: &Path,
+    full: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let restore_folder = format!(
+        "/tmp/influx_restore_{}",
+        chrono::Utc::now().format("%Y-%m-%d_%H%M%S")
+    );
+    let remote_archive = format!("{}.tar.gz", restore_folder);
+
+    // The upload, the restore and the cleanup share a single connection
+    let session = open_session(target)?;
+    say!("Uploading {} to {}", archive.display(), remote_archive);
+    send_file(&session, archive, &remote_archive, 0o644, target.sftp)?;
+
+    let output = run_command(
+        &session,
+        &format!(
+            "mkdir -p {0} && tar xzf {1} -C {0}",
+            shell_quote(&restore_folder),
+            shell_quote(&remote_archive)
+        ),
     )?;
+    let output = if output.status == 0 {
+        say!("Restoring InfluxDB from {} ..", restore_folder);
+        run_command(
+            &session,
+            &format!(
+                "influx restore {}{}",
+                if full { "--full " } else { "" },
+                shell_quote(&restore_folder)
+            ),
+        )?
+    } else {
+        output
+    };
+    // Don't leave a copy of the database in /tmp, whether the restore worked or not
+    run_command(
+        &session,
+        &format!(
+            "rm -rf {} {}",
+            shell_quote(&restore_folder),
+            shell_quote(&remote_archive)
+        ),
+    )?;
+    if output.status != 0 {
+        return Err(format!("failed to restore the backup: {}", output.stderr.trim()).into());
+    }
+    if !output.stdout.trim().is_empty() {
+        say!("{}", output.stdout.trim_end());
+    }
+    report!("InfluxDB restore completed successfully.");
+    Ok(())
+}
 
-    println!(
-        "Backup completed successfully. Files are located at: {}",
-        local_backup_path
+fn receive_file(
+    session: &Session,
+    remote_path: &str,
+    local_path: &Path,
+    max_rate: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    say!("Downloading {} ..", remote_path);
+    let (mut remote_file, stat) = session.scp_recv(Path::new(remote_path))?;
+    let mut limit = RateLimit::new(max_rate);
+    log::debug!(
+        "Downloading {} ({} bytes) to {}",
+        remote_path,
+        stat.size(),
+        local_path.display()
     );
+    let mut local_file = File::create(local_path)?;
+    let mut throttled = Throttled {
+        inner: &mut remote_file,
+        limit: &mut limit,
+    };
+    let copied = match transfer_progress(stat.size()) {
+        Some(bar) => {
+            let copied = std::io::copy(&mut bar.wrap_read(&mut throttled), &mut local_file)?;
+            bar.finish();
+            copied
+        }
+        None => std::io::copy(&mut throttled, &mut local_file)?,
+    };
+
+    // Drain the SCP channel so the session can be reused afterwards
+    remote_file.send_eof()?;
+    remote_file.wait_eof()?;
+    remote_file.close()?;
+    remote_file.wait_close()?;
+
+    if copied != stat.size() {
+        return Err(format!(
+            "downloaded {} bytes of {}, expected {}",
+            copied,
+            remote_path,
+            stat.size()
+        )
+        .into());
+    }
+    say!("Copied {} ({} bytes)", local_path.display(), stat.size());
     Ok(())
 }
 
+fn execute_command(session: &Session, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let output = run_command(session, command)?;
+    if !output.stdout.trim().is_empty() {
+        say!("Command output: {}", output.stdout);
+    }
+    if output.status != 0 {
+        return Err(command_failed(command, &output).into());
+    }
+    say!("Command executed successfully.");
+    Ok(())
+}
+
+#[allow(dead_code)] // kept as a standalone entry point
 pub fn execute_command_over_ssh(
-    remote_host: &str,
-    username: &str,
-    password: &str,
+    // Runs a command on the remote host and fails when it exits with an error
+    target: &SshTarget,
     command: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let tcp = TcpStream::connect(remote_host)?;
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
-    session.userauth_password(username, password)?;
+    let session = open_session(target)?;
+    execute_command(&session, command)
+}
+
+fn copy_directory(
+    session: &Session,
+    remote_directory: &str,
+    local_directory: &str,
+    force: bool,
+    max_rate: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // SFTP reports each entry's type, so subdirectories can be walked instead of failing in scp
+    let sftp = session.sftp()?;
+    let mut files = Vec::new();
+    list_directory_entries(
+        &sftp,
+        Path::new(remote_directory),
+        Path::new(local_directory),
+        &mut files,
+    )?;
+
+    // The sizes from the listing give the progress bar its total before anything is copied
+    let total = files.iter().map(|(_, _, size)| size).sum();
+    let progress = transfer_progress(total);
+    let mut limit = RateLimit::new(max_rate);
+    let mut skipped = 0;
+    for (remote_path, local_path, size) in &files {
+        // A local file of the same size was copied by an earlier, interrupted run
+        if !force && std::fs::metadata(local_path).is_ok_and(|metadata| metadata.len() == *size) {
+            log::debug!("Skipping {}, already downloaded", remote_path.display());
+            if let Some(bar) = &progress {
+                bar.inc(*size);
+            }
+            skipped += 1;
+            continue;
+        }
+        log::debug!(
+            "Downloading {} ({} bytes) to {}",
+            remote_path.display(),
+            size,
+            local_path.display()
+        );
+        let mut remote_file = Throttled {
+            inner: sftp.open(remote_path)?,
+            limit: &mut limit,
+        };
+        let mut local_file = File::create(local_path)?;
+        match &progress {
+            Some(bar) => {
+                std::io::copy(&mut bar.wrap_read(&mut remote_file), &mut local_file)?;
+            }
+            None => {
+                let size = std::io::copy(&mut remote_file, &mut local_file)?;
+                say!("Copied file {} ({} bytes)", remote_path.display(), size);
+            }
+        }
+    }
+    if let Some(bar) = progress {
+        bar.finish();
+        say!("Copied {} files ({} bytes)", files.len() - skipped, total);
+    }
+    if skipped > 0 {
+        say!("Skipped {} files that were already downloaded", skipped);
+    }
 
-    let mut channel = session.channel_session()?;
-    channel.exec(command)?;
-    let mut s = String::new();
-    channel.read_to_string(&mut s)?;
-    println!("Command output: {}", s);
-    channel.send_eof()?;
-    channel.wait_eof()?;
-    channel.wait_close()?;
-    println!("Command executed successfully.");
     Ok(())
 }
 
+#[allow(dead_code)] // kept as a standalone entry point
 pub fn copy_directory_over_ssh(
-    remote_host: &str,
-    username: &str,
-    password: &str,
+    // Downloads a remote directory with everything below it
+    target: &SshTarget,
     remote_directory: &str,
     local_directory: &str,
+    force: bool,
+    max_rate: Option<u64>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Establish an SSH session
-    let tcp = TcpStream::connect(remote_host)?;
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
-    session.userauth_password(username, password)?;
-
-    // Execute a command to list files in the remote directory
-    let mut channel = session.channel_session()?;
-    let list_command = format!("ls {}", remote_directory);
-    channel.exec(&list_command)?;
-    let mut file_list = String::new();
-    channel.read_to_string(&mut file_list)?;
-    channel.wait_close()?;
-    let file_list: Vec<&str> = file_list.lines().collect();
+    let session = open_session(target)?;
+    copy_directory(&session, remote_directory, local_directory, force, max_rate)
+}
 
-    // Iterate over each file name and copy it to the local directory
-    for file_name in file_list {
-        let remote_file_path = format!("{}/{}", remote_directory, file_name);
-        let local_file_path = Path::new(local_directory).join(file_name);
+fn list_directory_entries(
+    // Creates the local directories and collects every remote file with its local path and size
+    sftp: &ssh2::Sftp,
+    remote_directory: &Path,
+    local_directory: &Path,
+    files: &mut Vec<(PathBuf, PathBuf, u64)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(local_directory)?;
 
-        // Start SCP download for the remote file
-        let (mut remote_file, stat) = session.scp_recv(Path::new(&remote_file_path))?;
-        let mut local_file = File::create(local_file_path)?;
+    for (remote_path, stat) in sftp.readdir(remote_directory)? {
+        let file_name = match remote_path.file_name() {
+            Some(name) if name != "." && name != ".." => name,
+            _ => continue,
+        };
+        let local_path = local_directory.join(file_name);
 
-        // Copy the file content
-        std::io::copy(&mut remote_file, &mut local_file)?;
+        if stat.is_dir() {
+            say!("Entering directory {}", remote_path.display());
+            list_directory_entries(sftp, &remote_path, &local_path, files)?;
+            continue;
+        }
+        files.push((remote_path, local_path, stat.size.unwrap_or(0)));
+    }
+
+    Ok(())
+}
 
-        println!("Copied {} ({} bytes)", file_name, stat.size());
+fn transfer_progress(total: u64) -> Option<ProgressBar> {
+    // A bar only makes sense on a terminal, logs and pipes keep the plain per-file lines
+    if !std::io::stdout().is_terminal()
+        || crate::output::is_quiet()
+        || crate::output::is_capturing()
+    {
+        return None;
     }
+    let bar = ProgressBar::new(total);
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}")
+            .unwrap(),
+    );
+    Some(bar)
+}
+
+pub fn pull_telegraf_config(
+    // Downloads the remote telegraf.conf over SFTP to a local path
+    remote_path: &str,
+    local_path: &Path,
+    target: &SshTarget,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session = open_session(target)?;
+    let sftp = session.sftp()?;
+
+    log::debug!(
+        "Downloading {} to {} over SFTP",
+        remote_path,
+        local_path.display()
+    );
+    let mut remote_file = sftp.open(Path::new(remote_path))?;
+    let mut local_file = File::create(local_path)?;
+    let size = std::io::copy(&mut remote_file, &mut local_file)?;
 
+    report!(
+        "Downloaded {} to {} ({} bytes)",
+        remote_path,
+        local_path.display(),
+        size
+    );
     Ok(())
 }
 
 pub fn backup_grafana_config(
-    host: &str,
-    username: &str,
-    password: &str,
+    target: &SshTarget,
+    local_directory: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Establish SSH connection
-    let tcp = TcpStream::connect(host)?;
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
-    session.userauth_password(username, password)?;
+    let session = open_session(target)?;
 
     // Assuming Grafana config is stored in /etc/grafana/grafana.ini
     let remote_path = Path::new("/etc/grafana/grafana.ini");
-    let local_path = "grafana_backup.ini";
+    let local_path = local_directory.join("grafana_backup.ini");
+    std::fs::create_dir_all(local_directory)?;
 
     // Create an SFTP session
     let sftp = session.sftp()?;
@@ -265,10 +1426,67 @@ pub fn backup_grafana_config(
     remote_file.read_to_end(&mut contents)?;
 
     // Write to local file
-    let mut local_file = File::create(local_path)?;
+    let mut local_file = File::create(&local_path)?;
     local_file.write_all(&contents)?;
+    log::debug!("Wrote {} bytes to {}", contents.len(), local_path.display());
 
-    println!("Grafana configuration backed up to {}", local_path);
+    report!(
+        "Grafana configuration backed up to {}",
+        local_path.display()
+    );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The blob of an ECDSA host key, 104 bytes so its base64 needs padding.
+    const ECDSA_KEY: &str = "0000001365636473612d736861322d6e69737470323536000000086e69737470323536000000410440289c6b3ae320d5139489253e2801942cc26d3a3f70f52fbfbcc554e4ef5d1cfe6d2b545a6b3ad031a9df862763c75baa15365d23caffd78d65014dd31af1fa";
+    /// The line `ssh-keyscan -p 2222 192.168.0.10` prints for it.
+    const ECDSA_LINE: &str = "[192.168.0.10]:2222 ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBEAonGs64yDVE5SJJT4oAZQswm06P3D1L7+8xVTk710c/m0rVFprOtAxqd+GJ2PHW6oVNl0jyv/XjWUBTdMa8fo=";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn base64_pads_only_when_asked() {
+        assert_eq!(base64(b"", true), "");
+        assert_eq!(base64(b"f", true), "Zg==");
+        assert_eq!(base64(b"fo", true), "Zm8=");
+        assert_eq!(base64(b"foo", true), "Zm9v");
+        assert_eq!(base64(b"f", false), "Zg");
+        assert_eq!(base64(b"fo", false), "Zm8");
+    }
+
+    #[test]
+    fn fingerprint_matches_ssh_keygen() {
+        let hash = Sha256::digest(from_hex(ECDSA_KEY));
+        assert_eq!(
+            base64(&hash, false),
+            "WUs/zf/Hf525uqohIDeBbs57y/3eo87FyUdaDaBfN4Q"
+        );
+    }
+
+    #[test]
+    fn known_host_line_matches_ssh_keyscan() {
+        let path = std::env::temp_dir().join(format!("known_hosts_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        append_known_host(&path, "[192.168.0.10]:2222", &from_hex(ECDSA_KEY)).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, format!("{}\n", ECDSA_LINE));
+    }
+
+    #[test]
+    fn malformed_key_is_rejected() {
+        let path = std::env::temp_dir().join(format!("known_hosts_bad_{}", std::process::id()));
+        assert!(append_known_host(&path, "host", &[0, 0, 0, 9, b'x']).is_err());
+        assert!(!path.exists());
+    }
+}
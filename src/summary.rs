@@ -0,0 +1,113 @@
+use crate::format::{duration_seconds, OpcGroup};
+use serde::Serialize;
+use std::path::Path;
+
+/// Machine readable account of a generation run, printed by --json-summary.
+#[derive(Serialize)]
+pub struct Summary {
+    pub xml_files: usize,
+    /// XML files that couldn't be parsed or had no variables, and were left out of the config.
+    pub skipped_files: Vec<String>,
+    pub groups: Vec<GroupSummary>,
+    /// Where the config was written, unset on a dry run.
+    pub output: Option<String>,
+    pub sent: bool,
+    /// Roughly how many values the inputs produce per second, see `points_per_second`.
+    pub points_per_second: f64,
+}
+
+/// One input group of the generated config.
+#[derive(Serialize)]
+pub struct GroupSummary {
+    pub name: String,
+    pub source: String,
+    /// Either "listener" or "standard".
+    pub kind: &'static str,
+    pub nodes: usize,
+}
+
+impl Summary {
+    pub fn new(
+        xml_files: usize,
+        skipped_files: Vec<String>,
+        groups: &[OpcGroup],
+        output: Option<&Path>,
+    ) -> Summary {
+        Summary {
+            xml_files,
+            skipped_files,
+            groups: groups
+                .iter()
+                .map(|group| GroupSummary {
+                    name: group.name.clone(),
+                    source: group.source.clone(),
+                    kind: if group.is_listener {
+                        "listener"
+                    } else {
+                        "standard"
+                    },
+                    nodes: group.nodes.len(),
+                })
+                .collect(),
+            output: output.map(|path| path.display().to_string()),
+            sent: false,
+            points_per_second: points_per_second(groups),
+        }
+    }
+}
+
+/// Estimates the values written per second: one per node and interval, the group's polling
+/// interval for standard inputs and the sampling interval of listeners. Listeners only report
+/// changes, so for them this is the most they can produce.
+pub fn points_per_second(groups: &[OpcGroup]) -> f64 {
+    groups
+        .iter()
+        .flat_map(|group| {
+            group.nodes.iter().map(move |node| {
+                let interval = match &node.sampling_interval {
+                    Some(interval) if group.is_listener => interval,
+                    _ => &group.interval,
+                };
+                duration_seconds(interval)
+                    .filter(|seconds| *seconds > 0.0)
+                    .map_or(0.0, |seconds| 1.0 / seconds)
+            })
+        })
+        .sum()
+}
+
+/// An XML file left out of the config, with what was answered for it before it failed.
+pub struct FailedFile {
+    pub file: String,
+    pub error: String,
+    pub answers: Vec<(String, String)>,
+}
+
+pub fn write_error_log(path: &Path, failures: &[FailedFile]) -> std::io::Result<()> {
+    // One block per file, so a long run can be repaired file by file
+    let mut log = format!(
+        "Generation run at {}\n",
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+    );
+    for failure in failures {
+        log.push_str(&format!("\n{}\n  error: {}\n", failure.file, failure.error));
+        for (prompt, answer) in &failure.answers {
+            log.push_str(&format!("  {}: {}\n", prompt, answer));
+        }
+    }
+    std::fs::write(path, log)
+}
+
+pub fn write_summary(
+    summary: &Summary,
+    destination: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // "-" prints to stdout, anything else is a file path
+    let json = serde_json::to_string_pretty(summary)?;
+    if destination == "-" {
+        println!("{}", json);
+    } else {
+        std::fs::write(destination, json + "\n")?;
+    }
+    Ok(())
+}